@@ -0,0 +1,184 @@
+use crate::util::ActionContent;
+use crate::worldgen::{Chunk, Coords_f32, Coords_i32, Entity, Tile};
+use godot::prelude::*;
+
+// Typed views of the world types for GDScript. They are copies made on the main thread as
+// results come in, so nothing in Godot holds on to anything the connection task still uses.
+
+fn vector3(coords: &Coords_f32) -> Vector3 {
+    Vector3::new(coords.x.as_f32(), coords.y.as_f32(), coords.z.as_f32())
+}
+fn vector3i(coords: &Coords_i32) -> Vector3i {
+    Vector3i::new(coords.x, coords.y, coords.z)
+}
+
+#[derive(GodotClass)]
+#[class(no_init, base=RefCounted)]
+pub struct GEntity {
+    #[var]
+    index: i64,
+    #[var]
+    name: GString,
+    // Name of the EntityType, e.g. "Human"
+    #[var]
+    etype: GString,
+    #[var]
+    coords: Vector3,
+    #[var]
+    ccoords: Vector3i,
+    #[var]
+    ang: f32,
+    #[var]
+    traj: f32,
+    #[var]
+    world: i64,
+    #[var]
+    health: i64,
+}
+impl GEntity {
+    pub fn from(entity: &Entity) -> Gd<GEntity> {
+        Gd::from_object(GEntity {
+            index: entity.index as i64,
+            name: GString::from(entity.name.clone()),
+            etype: GString::from(format!("{:?}", entity.etype)),
+            coords: vector3(&entity.coords),
+            ccoords: vector3i(&entity.ccoords),
+            ang: entity.ang.as_f32(),
+            traj: entity.traj.as_f32(),
+            world: entity.current_world as i64,
+            health: entity.stats.health as i64,
+        })
+    }
+}
+
+#[derive(GodotClass)]
+#[class(no_init, base=RefCounted)]
+pub struct GTile {
+    #[var]
+    index: i64,
+    // In tiles; z is the height
+    #[var]
+    coords: Vector3i,
+    #[var]
+    ttype: GString,
+    // Same numbering as GChunk.ttypes
+    #[var]
+    ttype_id: i32,
+    holds: Option<Entity>,
+}
+#[godot_api]
+impl GTile {
+    // Whatever stands on the tile, if anything.
+    #[func]
+    fn holds(&self) -> Option<Gd<GEntity>> {
+        self.holds.as_ref().map(GEntity::from)
+    }
+}
+impl GTile {
+    pub fn from(tile: &Tile) -> Gd<GTile> {
+        Gd::from_object(GTile {
+            index: tile.index as i64,
+            coords: vector3i(&tile.coords),
+            ttype: GString::from(format!("{:?}", tile.ttype)),
+            ttype_id: tile.ttype.clone() as i32,
+            holds: tile.holds.clone(),
+        })
+    }
+}
+
+// Tiles go row by row, CHUNK_SIZE to a row. Their heights and types come as packed arrays so
+// meshes can be built without a call per tile; tile() has the rest of a single one.
+#[derive(GodotClass)]
+#[class(no_init, base=RefCounted)]
+pub struct GChunk {
+    #[var]
+    index: i64,
+    #[var]
+    coords: Vector3i,
+    #[var]
+    hash: i64,
+    #[var]
+    heights: PackedInt32Array,
+    // TileType by its position in the enum, Grass being 0
+    #[var]
+    ttypes: PackedByteArray,
+    #[var]
+    entities: Array<Gd<GEntity>>,
+    tiles: Vec<Tile>,
+}
+#[godot_api]
+impl GChunk {
+    #[func]
+    fn tile_count(&self) -> i64 {
+        self.tiles.len() as i64
+    }
+    #[func]
+    fn tile(&self, i: i64) -> Option<Gd<GTile>> {
+        let i = usize::try_from(i).ok()?;
+        self.tiles.get(i).map(GTile::from)
+    }
+}
+impl GChunk {
+    pub fn from(chunk: &Chunk) -> Gd<GChunk> {
+        let heights: Vec<i32> = chunk.tiles.iter().map(|t| t.coords.z).collect();
+        let ttypes: Vec<u8> = chunk.tiles.iter().map(|t| t.ttype.clone() as u8).collect();
+        Gd::from_object(GChunk {
+            index: chunk.index as i64,
+            coords: vector3i(&chunk.coords),
+            // Only ever compared, so the sign does not matter
+            hash: chunk.hash as i64,
+            heights: PackedInt32Array::from(heights.as_slice()),
+            ttypes: PackedByteArray::from(ttypes.as_slice()),
+            entities: chunk.entities.iter().map(GEntity::from).collect(),
+            tiles: chunk.tiles.clone(),
+        })
+    }
+}
+
+// An action on its way to the server, as handed back by the action methods of Net. poll fills
+// it in once the request carrying it has been answered; the action itself takes effect on the
+// server's next tick, and shows in the chunks that come after.
+#[derive(GodotClass)]
+#[class(no_init, base=RefCounted)]
+pub struct GAction {
+    // Name of the ActionType, e.g. "ConstructCannon"
+    #[var]
+    action: GString,
+    #[var]
+    ang: f32,
+    #[var]
+    traj: f32,
+    #[var]
+    done: bool,
+    // Whether the server took the request; error says why not
+    #[var]
+    ok: bool,
+    #[var]
+    error: GString,
+    // Server tick of the answer
+    #[var]
+    tick: i64,
+}
+impl GAction {
+    pub fn from(action: &ActionContent) -> Gd<GAction> {
+        Gd::from_object(GAction {
+            action: GString::from(format!("{:?}", action.action_type)),
+            ang: action.ang.as_f32(),
+            traj: action.traj.as_f32(),
+            done: false,
+            ok: false,
+            error: GString::new(),
+            tick: -1,
+        })
+    }
+    pub fn answered(&mut self, result: Result<u64, String>) {
+        self.done = true;
+        match result {
+            Ok(tick) => {
+                self.ok = true;
+                self.tick = tick as i64;
+            }
+            Err(e) => self.error = GString::from(e),
+        }
+    }
+}
@@ -0,0 +1 @@
+/home/eino/repo/dimensioner/server/src/config.rs
\ No newline at end of file
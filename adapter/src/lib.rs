@@ -1,45 +1,388 @@
-use crate::net::*;
-use crate::util::{ClientData, ActionContent, ClientDataType};
+use crate::cache::ChunkCache;
+use crate::classes::{GAction, GChunk};
+use crate::config::Config;
+use crate::local::LocalServer;
+use crate::net::Connection;
+use crate::terrain::TerrainArrays;
+use crate::util::{ActionContent, ActionType, ClientData, ClientDataType, MovementIntent};
+use crate::worldgen::{
+    ChunkUpdate, Coords_f32, Coords_i32, Entity, HashableF32, TILE_SIZE, WORLD_SIZE,
+};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use godot::classes::ISprite2D;
 use godot::classes::Node;
 use godot::classes::Sprite2D;
 use godot::prelude::*;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use tokio::runtime::Runtime;
-use crate::worldgen::{Entity, Chunk};
-use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize)]
-struct GClientData {
-    id: usize,
-    coords: (f32,f32,f32),
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+lazy_static! {
+    // Chunks this far from the player's are kept, so terrain has its neighbours to stitch to
+    pub static ref VIEW_RADIUS: i32 = 1;
+}
+
+// What one round trip brought back: the player's entity index, the chunk it was asked around
+// and what changed in the chunks there.
+struct Fetched {
+    player: Option<usize>,
+    ccoords: Coords_i32,
+    tick: u64,
+    updates: Vec<ChunkUpdate>,
+}
+// An answered round trip, with the number of the action it carried if any.
+struct Answer {
+    action: Option<u64>,
+    fetched: Result<Fetched, String>,
+}
+
+// What Godot asks of the connection task.
+enum Request {
+    // Where Godot has the player in world coordinates
+    Position(Vector3),
+    Action(u64, ActionContent),
+    // (chunk index, hash) of the updates the main thread managed to apply
+    Applied(Vec<(usize, u64)>),
 }
 
 struct DimensionerExtension;
 #[gdextension]
 unsafe impl ExtensionLibrary for DimensionerExtension {}
+// Talks to the server from a background runtime, so nothing here ever waits on the network.
+// request_chunks and the action methods hand their requests to the connection task and return
+// at once; poll picks up whatever has come back since.
 #[derive(GodotClass)]
 #[class(base=Node)]
-struct Net {}
+struct Net {
+    // Never used directly, but the connection task only runs for as long as it is kept
+    _runtime: Runtime,
+    requests: UnboundedSender<Request>,
+    results: Receiver<Answer>,
+    player: Option<usize>,
+    chunks: ChunkCache,
+    // Sent actions by number, until their answer comes in
+    actions: HashMap<u64, Gd<GAction>>,
+    next_action: u64,
+}
 #[godot_api]
 impl INode for Net {
     fn init(base: Base<Node>) -> Self {
-        Self {}
+        let runtime = Runtime::new().expect("Failed to create Tokio runtime");
+        let (tx_requests, rx_requests) = unbounded_channel();
+        let (tx_results, rx_results) = unbounded();
+        runtime.spawn(serve(Connection::new(), rx_requests, tx_results));
+        Self {
+            _runtime: runtime,
+            requests: tx_requests,
+            results: rx_results,
+            player: None,
+            chunks: ChunkCache::new(),
+            actions: HashMap::new(),
+            next_action: 0,
+        }
     }
 }
 #[godot_api]
 impl Net {
+    // Asks for the chunks around the player, coords being where Godot has it in world
+    // coordinates. Returns immediately; the answer comes out of poll.
+    #[func]
+    fn request_chunks(&mut self, coords: Vector3) -> bool {
+        self.requests.send(Request::Position(coords)).is_ok()
+    }
+    // Actions happen where the server has the player. ang is the heading in radians and traj the
+    // elevation of whatever is fired. Each returns a GAction that poll fills in once answered.
+    #[func]
+    fn construct_cannon(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::ConstructCannon, ang, traj)
+    }
+    #[func]
+    fn construct_road(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::ConstructRoad, ang, traj)
+    }
+    #[func]
+    fn construct_shell(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::ConstructShell, ang, traj)
+    }
+    #[func]
+    fn construct_landmine(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::ConstructLandmine, ang, traj)
+    }
+    #[func]
+    fn construct_car(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::ConstructCar, ang, traj)
+    }
+    #[func]
+    fn interact(&mut self, ang: f32, traj: f32) -> Gd<GAction> {
+        self.act(ActionType::Interact, ang, traj)
+    }
+    // Asks for the chunks again without doing anything
     #[func]
-    fn transfer(&mut self, data: String) -> String {
-	let gdata: GClientData = serde_json::from_str(&data).expect("cdata deserialization failed");
-	let cdata = ClientData::from(Entity::gen_player(gdata.id, gdata.coords.0, gdata.coords.1, gdata.coords.2), ActionContent::new(), ClientDataType::Chunk);
-        let rt = Runtime::new().expect("Failed to create Tokio runtime");
-        let res = rt.block_on(async {
-            send_client_data(cdata).await
+    fn refresh(&mut self) -> Gd<GAction> {
+        self.act(ActionType::Refresh, 0.0, 0.0)
+    }
+    // Every chunk around the player if any of them changed since the last call, or none if
+    // nothing has.
+    #[func]
+    fn poll(&mut self) -> Array<Gd<GChunk>> {
+        let mut changed = false;
+        for answer in self.results.try_iter() {
+            if let Some(mut action) = answer.action.and_then(|id| self.actions.remove(&id)) {
+                let result = match &answer.fetched {
+                    Ok(fetched) => Ok(fetched.tick),
+                    Err(e) => Err(e.clone()),
+                };
+                action.bind_mut().answered(result);
+            }
+            match answer.fetched {
+                Ok(fetched) => {
+                    if fetched.player.is_some() {
+                        self.player = fetched.player;
+                    }
+                    let applied: Vec<(usize, u64)> = fetched
+                        .updates
+                        .iter()
+                        .filter(|u| self.chunks.apply(u, fetched.tick))
+                        .map(|u| (u.index(), u.hash()))
+                        .collect();
+                    if !applied.is_empty() {
+                        let _ = self.requests.send(Request::Applied(applied));
+                    }
+                    self.chunks.retain_around(&fetched.ccoords, *VIEW_RADIUS);
+                    changed |= !fetched.updates.is_empty();
+                }
+                Err(e) => godot_error!("Transfer failed: {}", e),
+            }
+        }
+        if !changed {
+            return Array::new();
+        }
+        self.chunks.chunks().map(GChunk::from).collect()
+    }
+    // Mesh arrays for the terrain of a chunk poll has handed out, ready for
+    // ArrayMesh.add_surface_from_arrays. Empty if the chunk is no longer around.
+    #[func]
+    fn terrain(&self, index: i64) -> VariantArray {
+        match self.chunks.chunks().find(|c| c.index as i64 == index) {
+            Some(chunk) => TerrainArrays::from(chunk, &self.chunks).to_surface(),
+            None => VariantArray::new(),
+        }
+    }
+    // Leaves the server for a world of the given seed run inside Godot, with nobody else in it.
+    // Generating it takes a while, during which requests wait as they would for a slow server.
+    #[func]
+    fn play_local(&mut self, seed: i64) {
+        let (tx_requests, rx_requests) = unbounded_channel();
+        let (tx_results, rx_results) = unbounded();
+        let mut config = Config::new();
+        config.seed = seed as u32;
+        self._runtime.spawn(async move {
+            match tokio::task::spawn_blocking(move || LocalServer::start(&config)).await {
+                Ok(Ok(server)) => serve(Connection::local(server), rx_requests, tx_results).await,
+                Ok(Err(e)) => {
+                    let _ = tx_results.send(Answer {
+                        action: None,
+                        fetched: Err(e),
+                    });
+                }
+                Err(e) => {
+                    let _ = tx_results.send(Answer {
+                        action: None,
+                        fetched: Err(format!("Generating the world failed: {}", e)),
+                    });
+                }
+            }
         });
-	serde_json::to_string(&res.unwrap()).expect("Could not parse chunks to string")
+        // Dropping the old sender ends the task that served the server
+        self.requests = tx_requests;
+        self.results = rx_results;
+        self.player = None;
+        self.chunks = ChunkCache::new();
+        for (_, mut action) in self.actions.drain() {
+            action
+                .bind_mut()
+                .answered(Err("Left the server before it answered".to_string()));
+        }
+    }
+    // Number of actions sent that have not been answered yet.
+    #[func]
+    fn pending_actions(&self) -> i64 {
+        self.actions.len() as i64
+    }
+    // Entity index the server gave this player, or -1 before joining.
+    #[func]
+    fn player_id(&self) -> i64 {
+        self.player.map_or(-1, |index| index as i64)
+    }
+}
+impl Net {
+    fn act(&mut self, action_type: ActionType, ang: f32, traj: f32) -> Gd<GAction> {
+        let action = ActionContent::from(action_type, HashableF32(ang), HashableF32(traj));
+        let mut gaction = GAction::from(&action);
+        self.next_action += 1;
+        match self
+            .requests
+            .send(Request::Action(self.next_action, action))
+        {
+            Ok(()) => {
+                self.actions.insert(self.next_action, gaction.clone());
+            }
+            Err(_) => gaction
+                .bind_mut()
+                .answered(Err("The connection task has stopped".to_string())),
+        }
+        gaction
+    }
+}
+// Owns the connection for the lifetime of the node. Godot asks every frame, faster than the
+// server answers, so positions that pile up during a round trip are skipped to the newest.
+// Actions are all sent, one round trip each, as a request only carries one.
+async fn serve(
+    mut connection: Connection,
+    mut requests: UnboundedReceiver<Request>,
+    results: Sender<Answer>,
+) {
+    // Where the server last had the player
+    let mut position: Option<Coords_f32> = None;
+    // Hash of every chunk the main thread has applied, so only what changed is sent again
+    let mut known: HashMap<usize, u64> = HashMap::new();
+    // Where Godot last had the player, which joining needs
+    let mut latest: Option<Vector3> = None;
+    while let Some(request) = requests.recv().await {
+        let mut next = Some(request);
+        let mut actions = vec![];
+        let mut asked = false;
+        while let Some(request) = next.take().or_else(|| requests.try_recv().ok()) {
+            match request {
+                Request::Position(coords) => {
+                    latest = Some(coords);
+                    asked = true;
+                }
+                Request::Action(id, action) => {
+                    actions.push((Some(id), action));
+                    asked = true;
+                }
+                Request::Applied(applied) => known.extend(applied),
+            }
+        }
+        // What was applied only goes along with the next request
+        if !asked {
+            continue;
+        }
+        let coords = match latest {
+            Some(coords) => coords,
+            None => {
+                for (id, _) in actions {
+                    let fetched = Err("No position requested yet".to_string());
+                    if results
+                        .send(Answer {
+                            action: id,
+                            fetched: fetched,
+                        })
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                continue;
+            }
+        };
+        if actions.is_empty() {
+            actions.push((None, ActionContent::new()));
+        }
+        for (id, action) in actions {
+            let fetched = fetch(&mut connection, &mut position, &mut known, coords, action).await;
+            if results
+                .send(Answer {
+                    action: id,
+                    fetched: fetched,
+                })
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+async fn fetch(
+    connection: &mut Connection,
+    position: &mut Option<Coords_f32>,
+    known: &mut HashMap<usize, u64>,
+    coords: Vector3,
+    action: ActionContent,
+) -> Result<Fetched, String> {
+    // The index is the server's to hand out
+    let player = Entity::gen_player(0, coords.x, coords.y, coords.z);
+    let ccoords = player.ccoords.clone();
+    if connection.session().is_none() {
+        connection
+            .join(player.clone())
+            .await
+            .map_err(|e| format!("Join failed: {}", e))?;
+    }
+    // The server moves the player, so ask it to step towards where Godot has it
+    let area = ClientDataType::Rect {
+        w: *VIEW_RADIUS,
+        h: *VIEW_RADIUS,
+    };
+    // The main thread keeps the same chunks as we do here
+    known.retain(|i, _| {
+        let (x, y) = (
+            (*i % *WORLD_SIZE as usize) as i32,
+            (*i / *WORLD_SIZE as usize) as i32,
+        );
+        (x - ccoords.x).abs() <= *VIEW_RADIUS && (y - ccoords.y).abs() <= *VIEW_RADIUS
+    });
+    let mut cdata = ClientData::from(player.clone(), action, area, ccoords.clone());
+    cdata.known_chunks = known.iter().map(|(i, h)| (*i, *h)).collect();
+    if let Some(position) = position.as_ref() {
+        cdata.intent = MovementIntent::from(
+            step(player.coords.x.as_f32() - position.x.as_f32()),
+            step(player.coords.y.as_f32() - position.y.as_f32()),
+            false,
+            false,
+        );
+    }
+    let updates = connection
+        .request(&cdata)
+        .await
+        .map_err(|e| e.to_string())?;
+    let index = connection.session().map(|s| s.entity_index);
+    if let Some(e) = updates
+        .iter()
+        .flat_map(|u| u.entities())
+        .find(|e| Some(e.index) == index)
+    {
+        *position = Some(e.coords.clone());
+    }
+    Ok(Fetched {
+        player: index,
+        ccoords: ccoords,
+        tick: connection.tick(),
+        updates: updates,
+    })
+}
+fn step(d: f32) -> i8 {
+    if d >= *TILE_SIZE as f32 {
+        1
+    } else if d <= -(*TILE_SIZE as f32) {
+        -1
+    } else {
+        0
     }
 }
+pub mod cache;
+pub mod classes;
+pub mod config;
+pub mod frame;
 pub mod lang;
+pub mod local;
 pub mod math;
 pub mod net;
+pub mod serving;
+pub mod simulation;
+pub mod snapshot;
+pub mod terrain;
 pub mod util;
 pub mod worldgen;
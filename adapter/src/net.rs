@@ -1 +1 @@
-/home/eino/repo/dimensioner/client-sdl2/src/net.rs
\ No newline at end of file
+/home/eino/repo/dimensioner/client/src/net.rs
\ No newline at end of file
@@ -0,0 +1 @@
+/home/eino/repo/dimensioner/server/src/simulation.rs
\ No newline at end of file
@@ -0,0 +1,135 @@
+use crate::cache::ChunkCache;
+use crate::worldgen::{Chunk, TileType, CHUNK_SIZE};
+use godot::classes::mesh::ArrayType;
+use godot::prelude::*;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    // Godot units per step of tile height. A tile is one unit across.
+    pub static ref HEIGHT_SCALE: f32 = 0.5;
+}
+
+pub fn tile_color(ttype: &TileType) -> Color {
+    match ttype {
+        TileType::Grass => Color::from_rgb(0.35, 0.6, 0.25),
+        TileType::Water => Color::from_rgb(0.15, 0.35, 0.7),
+        TileType::Sand => Color::from_rgb(0.85, 0.78, 0.55),
+        TileType::StoneSand => Color::from_rgb(0.7, 0.66, 0.55),
+        TileType::FarmLand => Color::from_rgb(0.45, 0.33, 0.2),
+        TileType::WetLand => Color::from_rgb(0.3, 0.45, 0.35),
+        TileType::Asphalt => Color::from_rgb(0.2, 0.2, 0.22),
+        TileType::Salt => Color::from_rgb(0.93, 0.93, 0.9),
+        TileType::Wood => Color::from_rgb(0.55, 0.38, 0.2),
+        TileType::Concrete => Color::from_rgb(0.6, 0.6, 0.6),
+        TileType::Granite => Color::from_rgb(0.5, 0.48, 0.47),
+    }
+}
+
+// Height of a tile corner, x and y in tiles. It is the average of the tiles that share the
+// corner, whichever chunk they are in, so two chunks put their common edge at the same height.
+fn corner_height(chunks: &ChunkCache, x: i32, y: i32) -> Option<f32> {
+    let heights: Vec<i32> = [(-1, -1), (0, -1), (-1, 0), (0, 0)]
+        .iter()
+        .filter_map(|(dx, dy)| chunks.fetch_tile(x + dx, y + dy))
+        .map(|t| t.coords.z)
+        .collect();
+    match heights.len() {
+        0 => None,
+        n => Some(heights.iter().sum::<i32>() as f32 / n as f32 * *HEIGHT_SCALE),
+    }
+}
+
+// Corner heights of a chunk plus a ring of corners around it for the normals at its edges.
+struct Corners {
+    size: i32,
+    heights: Vec<f32>,
+}
+impl Corners {
+    fn from(chunk: &Chunk, chunks: &ChunkCache) -> Corners {
+        let size = *CHUNK_SIZE as i32;
+        let (ox, oy) = (chunk.coords.x * size, chunk.coords.y * size);
+        let mut heights = vec![];
+        for y in -1..=size + 1 {
+            for x in -1..=size + 1 {
+                // Only the ring can be missing, when the chunk beyond has not arrived
+                let inner = (x.clamp(0, size), y.clamp(0, size));
+                let height = corner_height(chunks, ox + x, oy + y)
+                    .or_else(|| corner_height(chunks, ox + inner.0, oy + inner.1))
+                    .unwrap_or(0.0);
+                heights.push(height);
+            }
+        }
+        Corners {
+            size: size,
+            heights: heights,
+        }
+    }
+    // x and y from -1 to size + 1, relative to the chunk.
+    fn height(&self, x: i32, y: i32) -> f32 {
+        let stride = self.size + 3;
+        self.heights[((y + 1) * stride + x + 1) as usize]
+    }
+    // From the corners on either side, which both chunks at an edge see alike.
+    fn normal(&self, x: i32, y: i32) -> Vector3 {
+        Vector3::new(
+            self.height(x - 1, y) - self.height(x + 1, y),
+            2.0,
+            self.height(x, y - 1) - self.height(x, y + 1),
+        )
+        .normalized()
+    }
+}
+
+// The terrain of one chunk in the arrays ArrayMesh.add_surface_from_arrays takes. Every tile is
+// a quad of its own so its colour does not bleed into its neighbours; the corners are shared in
+// position and normal only. Vertices are relative to the chunk's corner, with Godot's y up and
+// the world's y along z.
+pub struct TerrainArrays {
+    pub vertices: PackedVector3Array,
+    pub normals: PackedVector3Array,
+    pub uvs: PackedVector2Array,
+    pub colors: PackedColorArray,
+    pub indices: PackedInt32Array,
+}
+impl TerrainArrays {
+    pub fn from(chunk: &Chunk, chunks: &ChunkCache) -> TerrainArrays {
+        let corners = Corners::from(chunk, chunks);
+        let size = *CHUNK_SIZE as i32;
+        let mut arrays = TerrainArrays {
+            vertices: PackedVector3Array::new(),
+            normals: PackedVector3Array::new(),
+            uvs: PackedVector2Array::new(),
+            colors: PackedColorArray::new(),
+            indices: PackedInt32Array::new(),
+        };
+        for tile in &chunk.tiles {
+            let x = tile.coords.x - chunk.coords.x * size;
+            let y = tile.coords.y - chunk.coords.y * size;
+            let base = arrays.vertices.len() as i32;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (cx, cy) = (x + dx, y + dy);
+                arrays
+                    .vertices
+                    .push(Vector3::new(cx as f32, corners.height(cx, cy), cy as f32));
+                arrays.normals.push(corners.normal(cx, cy));
+                arrays.uvs.push(Vector2::new(dx as f32, dy as f32));
+                arrays.colors.push(tile_color(&tile.ttype));
+            }
+            // Clockwise seen from above, which Godot takes as the front
+            for i in [0, 1, 2, 1, 3, 2] {
+                arrays.indices.push(base + i);
+            }
+        }
+        arrays
+    }
+    pub fn to_surface(self) -> VariantArray {
+        let mut surface = VariantArray::new();
+        surface.resize(ArrayType::MAX.ord() as usize, &Variant::nil());
+        surface.set(ArrayType::VERTEX.ord() as usize, self.vertices.to_variant());
+        surface.set(ArrayType::NORMAL.ord() as usize, self.normals.to_variant());
+        surface.set(ArrayType::TEX_UV.ord() as usize, self.uvs.to_variant());
+        surface.set(ArrayType::COLOR.ord() as usize, self.colors.to_variant());
+        surface.set(ArrayType::INDEX.ord() as usize, self.indices.to_variant());
+        surface
+    }
+}
@@ -1 +1 @@
-/home/eino/repo/dimensioner/client-sdl2/src/util.rs
\ No newline at end of file
+/home/eino/repo/dimensioner/client/src/util.rs
\ No newline at end of file
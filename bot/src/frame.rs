@@ -0,0 +1 @@
+/home/eino/repo/dimensioner/client/src/frame.rs
\ No newline at end of file
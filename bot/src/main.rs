@@ -0,0 +1,290 @@
+mod config;
+mod frame;
+mod local;
+mod math;
+mod net;
+mod serving;
+mod simulation;
+mod snapshot;
+mod stats;
+mod util;
+mod worldgen;
+
+use config::Config;
+use lazy_static::lazy_static;
+use local::LocalServer;
+use net::{Connection, SERVER_ADDRESS};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use stats::Stats;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use util::{ActionContent, ActionType, ClientData, ClientDataType, MovementIntent, Subscription};
+use worldgen::{chunk_index, ChunkUpdate, Entity, HashableF32, CHUNK_SIZE, TILE_SIZE, WORLD_SIZE};
+
+lazy_static! {
+    pub static ref USAGE: String = "Usage: dimensioner_bot [--server addr] [--bots n] [--duration secs] \
+[--interval ms] [--action-chance x] [--spread chunks] [--view-distance n] [--local seed]"
+        .to_string();
+    // What a bot does when it acts, picked evenly
+    pub static ref BOT_ACTIONS: Vec<ActionType> = vec![
+        ActionType::ConstructRoad,
+        ActionType::ConstructCannon,
+        ActionType::Interact,
+    ];
+    // Chance per request that a walking bot picks a new direction
+    pub static ref TURN_CHANCE: f64 = 0.1;
+    pub static ref JOIN_RETRY: Duration = Duration::from_secs(1);
+}
+
+#[derive(Clone, Debug)]
+pub struct BotConfig {
+    pub server: String,
+    // Seed of a world run in this process, in place of the server
+    pub local: Option<u32>,
+    pub bots: usize,
+    pub duration: Duration,
+    // Pause between the requests of one bot
+    pub interval: Duration,
+    pub action_chance: f64,
+    // Bots start this many chunks around the middle of the world at most
+    pub spread: i32,
+    pub view_distance: i32,
+}
+impl BotConfig {
+    pub fn new() -> BotConfig {
+        BotConfig {
+            server: SERVER_ADDRESS.clone(),
+            local: None,
+            bots: 10,
+            duration: Duration::from_secs(30),
+            interval: Duration::from_millis(125),
+            action_chance: 0.1,
+            spread: 4,
+            view_distance: 2,
+        }
+    }
+    pub fn from_args(args: &[String]) -> Result<BotConfig, String> {
+        let mut config = BotConfig::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or(format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                "--server" => config.server = value()?,
+                "--local" => config.local = Some(parse(arg, &value()?)?),
+                "--bots" => config.bots = parse(arg, &value()?)?,
+                "--duration" => config.duration = Duration::from_secs(parse(arg, &value()?)?),
+                "--interval" => config.interval = Duration::from_millis(parse(arg, &value()?)?),
+                "--action-chance" => config.action_chance = parse(arg, &value()?)?,
+                "--spread" => config.spread = parse(arg, &value()?)?,
+                "--view-distance" => config.view_distance = parse(arg, &value()?)?,
+                other => return Err(format!("Unknown argument {}\n{}", other, *USAGE)),
+            }
+        }
+        if config.bots == 0 {
+            return Err("At least one bot is needed".to_string());
+        }
+        if !(0.0..=1.0).contains(&config.action_chance) {
+            return Err(format!(
+                "Action chance must be 0-1, got {}",
+                config.action_chance
+            ));
+        }
+        Ok(config)
+    }
+}
+impl fmt::Display for BotConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.local {
+            Some(seed) => writeln!(f, "Server:         local, seed {}", seed)?,
+            None => writeln!(f, "Server:         {}", self.server)?,
+        }
+        writeln!(
+            f,
+            "Bots:           {} for {:?}, a request every {:?}",
+            self.bots, self.duration, self.interval
+        )?;
+        write!(
+            f,
+            "Behaviour:      action chance {}, spread {} chunks, view distance {}",
+            self.action_chance, self.spread, self.view_distance
+        )
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {} for {}: {}", value, flag, e))
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match BotConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("{}", config);
+    let local = config.local.map(|seed| {
+        let mut server_config = Config::new();
+        server_config.seed = seed;
+        match LocalServer::start(&server_config) {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let deadline = Instant::now() + config.duration;
+    let bots: Vec<_> = (0..config.bots)
+        .map(|id| tokio::spawn(run_bot(id, config.clone(), local.clone(), deadline)))
+        .collect();
+    let mut total = Stats::new();
+    for bot in bots {
+        match bot.await {
+            Ok(stats) => total.merge(stats),
+            Err(e) => eprintln!("Bot failed: {}", e),
+        }
+    }
+    println!("{}", total);
+}
+
+// One simulated player. It walks about at random, now and then does something where it stands,
+// and keeps the chunks around it up to date the way the real client does.
+async fn run_bot(
+    id: usize,
+    config: BotConfig,
+    local: Option<LocalServer>,
+    deadline: Instant,
+) -> Stats {
+    let mut stats = Stats::new();
+    stats.bots = 1;
+    let started = Instant::now();
+    let mut rng = StdRng::from_entropy();
+    let mut connection = match local {
+        Some(server) => Connection::local(server),
+        None => Connection::from(&config.server),
+    };
+
+    let middle = (*WORLD_SIZE * *CHUNK_SIZE * *TILE_SIZE) as f32 / 2.0;
+    let spread = (config.spread.max(0) as u32 * *CHUNK_SIZE * *TILE_SIZE) as f32;
+    let mut me = Entity::gen_player(
+        0,
+        middle + rng.gen_range(-spread..=spread),
+        middle + rng.gen_range(-spread..=spread),
+        0.0,
+    );
+    me.name = format!("Bot {}", id);
+    let session = loop {
+        match connection.join(me.clone()).await {
+            Ok(session) => break session,
+            Err(e) => stats.error(&e),
+        }
+        if Instant::now() + *JOIN_RETRY > deadline {
+            stats.elapsed = started.elapsed();
+            return stats;
+        }
+        tokio::time::sleep(*JOIN_RETRY).await;
+    };
+    stats.joined = 1;
+    me.index = session.entity_index;
+
+    let mut known_chunks: HashMap<usize, u64> = HashMap::new();
+    let mut updates: Vec<ChunkUpdate> = vec![];
+    let subscription = Subscription::from(
+        ClientDataType::Radius(config.view_distance),
+        me.ccoords.clone(),
+        Some(me.index),
+    );
+    match connection.subscribe(subscription).await {
+        Ok(u) => updates.extend(u),
+        Err(e) => stats.error(&e),
+    }
+
+    let mut sequence = 0;
+    let mut direction = (0, 0);
+    while Instant::now() < deadline {
+        // A reconnect after the server let go of our entity rejoins under a new index
+        if let Some(session) = connection.session() {
+            me.index = session.entity_index;
+        }
+        for u in updates.drain(..) {
+            if let Some(e) = u.entities().iter().find(|e| e.index == me.index) {
+                me = e.clone();
+            }
+            known_chunks.insert(u.index(), u.hash());
+        }
+
+        if rng.gen_bool(*TURN_CHANCE) {
+            direction = (rng.gen_range(-1..=1), rng.gen_range(-1..=1));
+        }
+        let intent = MovementIntent::from(direction.0, direction.1, false, false);
+        let action = if rng.gen_bool(config.action_chance) {
+            stats.actions += 1;
+            ActionContent::from(
+                BOT_ACTIONS[rng.gen_range(0..BOT_ACTIONS.len())].clone(),
+                HashableF32(rng.gen_range(0.0..std::f32::consts::TAU)),
+                HashableF32(rng.gen_range(0.0..std::f32::consts::FRAC_PI_2)),
+            )
+        } else {
+            ActionContent::new()
+        };
+        let data_type = ClientDataType::Chunk;
+        let view_known_chunks = data_type
+            .chunk_coords(&me.ccoords)
+            .iter()
+            .filter_map(|c| chunk_index(c))
+            .filter_map(|i| known_chunks.get(&i).map(|h| (i, *h)))
+            .collect();
+        // Only moves are numbered, the server has nothing else to acknowledge
+        let numbered = if intent.is_moving() {
+            sequence += 1;
+            sequence
+        } else {
+            0
+        };
+        let data = ClientData {
+            ccoords: me.ccoords.clone(),
+            entity: me.clone(),
+            action: action,
+            data_type: data_type,
+            known_chunks: view_known_chunks,
+            intent: intent,
+            sequence: numbered,
+            // Filled in from the session by the connection
+            session: 0,
+        };
+
+        let sent = Instant::now();
+        stats.requests += 1;
+        match connection.request(&data).await {
+            Ok(u) => {
+                stats.latencies.push(sent.elapsed());
+                stats.updates += u.len();
+                updates.extend(u);
+            }
+            Err(e) => stats.error(&e),
+        }
+        let pushed = connection.take_pushed();
+        stats.pushed += pushed.len();
+        updates.extend(pushed);
+        tokio::time::sleep(config.interval).await;
+    }
+    stats.elapsed = started.elapsed();
+    stats
+}
@@ -0,0 +1,110 @@
+use crate::net::NetError;
+use crate::util::ServerError;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+// What one bot saw over its run. Bots keep their own and they are merged at the end.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub bots: usize,
+    pub joined: usize,
+    pub elapsed: Duration,
+    // Round trip of every answered request
+    pub latencies: Vec<Duration>,
+    pub requests: usize,
+    pub updates: usize,
+    pub pushed: usize,
+    pub actions: usize,
+    // Frames we could not decode, and messages the server could not decode
+    pub parse_errors: usize,
+    pub server_errors: usize,
+    pub connection_errors: usize,
+}
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            bots: 0,
+            joined: 0,
+            elapsed: Duration::ZERO,
+            latencies: vec![],
+            requests: 0,
+            updates: 0,
+            pushed: 0,
+            actions: 0,
+            parse_errors: 0,
+            server_errors: 0,
+            connection_errors: 0,
+        }
+    }
+    pub fn error(&mut self, e: &NetError) {
+        match e {
+            NetError::Io(e) if e.kind() == io::ErrorKind::InvalidData => self.parse_errors += 1,
+            NetError::Server(ServerError::Malformed(_)) => self.parse_errors += 1,
+            NetError::Server(_) => self.server_errors += 1,
+            NetError::Io(_) => self.connection_errors += 1,
+        }
+    }
+    pub fn merge(&mut self, other: Stats) {
+        self.bots += other.bots;
+        self.joined += other.joined;
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.latencies.extend(other.latencies);
+        self.requests += other.requests;
+        self.updates += other.updates;
+        self.pushed += other.pushed;
+        self.actions += other.actions;
+        self.parse_errors += other.parse_errors;
+        self.server_errors += other.server_errors;
+        self.connection_errors += other.connection_errors;
+    }
+    // p in 0-100. Expects the latencies sorted.
+    fn percentile(&self, p: usize) -> Duration {
+        match self.latencies.len() {
+            0 => Duration::ZERO,
+            n => self.latencies[(n - 1) * p / 100],
+        }
+    }
+    fn per_second(&self, n: usize) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            s if s > 0.0 => n as f64 / s,
+            _ => 0.0,
+        }
+    }
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sorted = self.clone();
+        sorted.latencies.sort();
+        writeln!(f, "Bots:           {} ({} joined)", self.bots, self.joined)?;
+        writeln!(f, "Duration:       {:.1}s", self.elapsed.as_secs_f64())?;
+        writeln!(
+            f,
+            "Requests:       {} ({:.1}/s), {} with an action",
+            self.requests,
+            self.per_second(self.requests),
+            self.actions
+        )?;
+        writeln!(
+            f,
+            "Chunk updates:  {} answered ({:.1}/s), {} pushed ({:.1}/s)",
+            self.updates,
+            self.per_second(self.updates),
+            self.pushed,
+            self.per_second(self.pushed)
+        )?;
+        writeln!(
+            f,
+            "Latency:        p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+            sorted.percentile(50).as_secs_f64() * 1000.0,
+            sorted.percentile(90).as_secs_f64() * 1000.0,
+            sorted.percentile(99).as_secs_f64() * 1000.0,
+            sorted.percentile(100).as_secs_f64() * 1000.0
+        )?;
+        write!(
+            f,
+            "Errors:         {} parse, {} server, {} connection",
+            self.parse_errors, self.server_errors, self.connection_errors
+        )
+    }
+}
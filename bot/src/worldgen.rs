@@ -0,0 +1 @@
+/home/eino/repo/dimensioner/server/src/worldgen.rs
\ No newline at end of file
@@ -1,29 +1,39 @@
 extends Net
 
+# Plays in a world generated inside Godot instead of on the server
+@export var single_player = false
+@export var world_seed = 0
 
-# Called when the node enters the scene tree for the first time.
+# Hands the player's position to the adapter, which fetches the chunks around it in the
+# background. Returns at once; what comes back is picked up by receive().
+func request():
+	var c = Globals.player_data.coords
+	self.request_chunks(Vector3(c[0], c[1], c[2]))
 
-# Function to request and parse chunk data
-func send():
-	var json = JSON.new()
-	var p = json.stringify(Globals.player_data)
-	var chunks = self.transfer(p)
-	var c = json.parse(chunks)
-	var json_data = json.data
-	if json_data == null:
+# The GChunks around the player if any changed since the last call, or null if none did
+func receive():
+	var chunks = self.poll()
+	if self.player_id() >= 0:
+		Globals.player_data.id = self.player_id()
+	if chunks.is_empty():
 		return null
-	for e in json_data[0].entities:
-		if e.index == Globals.player_data.id:
-			Globals.player_data.ccoords[0] = e.ccoords.x
-			Globals.player_data.ccoords[2] = e.ccoords.y
-			Globals.player_data.ccoords[1] = e.ccoords.z
-	return json_data
+	# Every chunk around the player comes back, not only the one it is in
+	for c in chunks:
+		for e in c.entities:
+			if e.index == Globals.player_data.id:
+				Globals.player_data.ccoords[0] = e.ccoords.x
+				Globals.player_data.ccoords[2] = e.ccoords.y
+				Globals.player_data.ccoords[1] = e.ccoords.z
+	return chunks
 func _ready():
-	send()
+	if single_player:
+		self.play_local(world_seed)
+	request()
 # Called every frame. 'delta' is the elapsed time since the previous frame.
 func _process(delta: float) -> void:
-	var chunks = send()
+	request()
+	var chunks = receive()
 	if chunks == null:
 		return
 	Globals.current_chunks = chunks
-	
+
@@ -1,6 +1,7 @@
 extends Node
 
-var current_chunks = [{}]
+# GChunks from the adapter
+var current_chunks = []
 var player_data = {"id": 0, "coords": [0.0,0.0,0.0], "ccoords": [0,0,0]}
 const TILE_SIZE = 16
 const CHUNK_SIZE = 16
@@ -0,0 +1,152 @@
+use crate::math::{lerp, lerp_angle};
+use crate::worldgen::{
+    chunk_index, Chunk, ChunkUpdate, Coords_i32, Entity, HashableF32, Tile, CHUNK_SIZE,
+};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // Until measured, assume the server runs at its default rate
+    pub static ref DEFAULT_TICK_RATE: f32 = 120.0;
+    pub static ref TICK_RATE_WINDOW: Duration = Duration::from_secs(1);
+    // Entities not heard of for this long are forgotten
+    pub static ref HISTORY_TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+// An entity as the server had it on a tick, and when that reached us.
+#[derive(Clone, Debug)]
+pub struct EntitySnapshot {
+    pub tick: u64,
+    pub received: Instant,
+    pub entity: Entity,
+}
+
+// Chunks as they were last received. The last two states of every entity in them are kept too,
+// so renderers can move entities smoothly from one to the other instead of jumping.
+pub struct ChunkCache {
+    chunks: HashMap<usize, Chunk>,
+    // Previous and current state by entity index
+    history: HashMap<usize, (Option<EntitySnapshot>, EntitySnapshot)>,
+    // Server ticks per second as seen from here, and where the current measurement started
+    tick_rate: f32,
+    window: Option<(u64, Instant)>,
+}
+impl ChunkCache {
+    pub fn new() -> ChunkCache {
+        ChunkCache {
+            chunks: HashMap::new(),
+            history: HashMap::new(),
+            tick_rate: *DEFAULT_TICK_RATE,
+            window: None,
+        }
+    }
+    // tick is the server tick the update was made on. False if a delta had nothing to apply to,
+    // in which case the chunk is still not known here.
+    pub fn apply(&mut self, update: &ChunkUpdate, tick: u64) -> bool {
+        let now = Instant::now();
+        self.observe(tick, now);
+        match update {
+            ChunkUpdate::Full(chunk) => {
+                for e in &chunk.entities {
+                    self.record(e, tick, now);
+                }
+                self.chunks.insert(chunk.index, chunk.clone());
+                true
+            }
+            ChunkUpdate::Delta(delta) => {
+                let applied = match self.chunks.get_mut(&delta.index) {
+                    Some(chunk) => chunk.apply(delta),
+                    None => false,
+                };
+                // An entity leaving the chunk may only have crossed into the next one, so its
+                // history stays until it times out
+                if applied {
+                    for e in &delta.entities {
+                        self.record(e, tick, now);
+                    }
+                }
+                applied
+            }
+        }
+    }
+    fn record(&mut self, entity: &Entity, tick: u64, received: Instant) {
+        let snapshot = EntitySnapshot {
+            tick: tick,
+            received: received,
+            entity: entity.clone(),
+        };
+        match self.history.get_mut(&entity.index) {
+            Some((previous, current)) if current.tick < tick => {
+                *previous = Some(std::mem::replace(current, snapshot));
+            }
+            // Several updates on the same tick, the last one is what the server ended up with
+            Some((_, current)) => *current = snapshot,
+            None => {
+                self.history.insert(entity.index, (None, snapshot));
+            }
+        }
+    }
+    // Measures the tick rate over a window at a time, so updates arriving in bursts do not throw it.
+    fn observe(&mut self, tick: u64, now: Instant) {
+        match self.window {
+            Some((start, since)) if now - since >= *TICK_RATE_WINDOW => {
+                if tick > start {
+                    self.tick_rate = (tick - start) as f32 / (now - since).as_secs_f32();
+                }
+                self.window = Some((tick, now));
+                self.history
+                    .retain(|_, (_, current)| now - current.received < *HISTORY_TIMEOUT);
+            }
+            Some(_) => {}
+            None => self.window = Some((tick, now)),
+        }
+    }
+    pub fn tick_rate(&self) -> f32 {
+        self.tick_rate
+    }
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.values()
+    }
+    pub fn contains(&self, index: usize) -> bool {
+        self.chunks.contains_key(&index)
+    }
+    // Where to draw an entity at the given time. It moves from its previous state to its current
+    // one over as long as the server took between them, so it is drawn one update behind.
+    pub fn interpolate(&self, entity: &Entity, now: Instant) -> Entity {
+        let (previous, current) = match self.history.get(&entity.index) {
+            Some((Some(previous), current)) => (previous, current),
+            _ => return entity.clone(),
+        };
+        // Nothing to slide between when it went through to another world
+        if previous.entity.current_world != current.entity.current_world {
+            return entity.clone();
+        }
+        let span = (current.tick - previous.tick) as f32 / self.tick_rate;
+        let t = match now.checked_duration_since(current.received) {
+            Some(elapsed) if span > 0.0 => (elapsed.as_secs_f32() / span).min(1.0),
+            _ => 1.0,
+        };
+        let (previous, current) = (&previous.entity, &current.entity);
+        let mut entity = entity.clone();
+        let (a, b) = (&previous.coords, &current.coords);
+        entity.coords.x = HashableF32(lerp(a.x.as_f32(), b.x.as_f32(), t));
+        entity.coords.y = HashableF32(lerp(a.y.as_f32(), b.y.as_f32(), t));
+        entity.coords.z = HashableF32(lerp(a.z.as_f32(), b.z.as_f32(), t));
+        entity.ang = HashableF32(lerp_angle(previous.ang.as_f32(), current.ang.as_f32(), t));
+        entity.traj = HashableF32(lerp_angle(previous.traj.as_f32(), current.traj.as_f32(), t));
+        entity
+    }
+    // Drops chunks more than radius chunks away, the server sends them again when they are needed.
+    pub fn retain_around(&mut self, ccoords: &Coords_i32, radius: i32) {
+        self.chunks.retain(|_, c| {
+            (c.coords.x - ccoords.x).abs() <= radius && (c.coords.y - ccoords.y).abs() <= radius
+        });
+    }
+    // x and y are in tiles, not world coordinates.
+    pub fn fetch_tile(&self, x: i32, y: i32) -> Option<&Tile> {
+        let size = *CHUNK_SIZE as i32;
+        let ccoords = Coords_i32::from((x.div_euclid(size), y.div_euclid(size), 0));
+        self.chunks.get(&chunk_index(&ccoords)?)?.tile_at(x, y)
+    }
+}
@@ -0,0 +1,246 @@
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+lazy_static! {
+    pub static ref MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+    pub static ref FRAME_HEADER_SIZE: usize = 4;
+    pub static ref READ_BUFFER_SIZE: usize = 65536;
+    pub static ref COMPRESSION_THRESHOLD: usize = 512;
+    pub static ref ZSTD_LEVEL: i32 = 3;
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+impl Compression {
+    // Preferred codecs first.
+    pub fn supported() -> Vec<Compression> {
+        vec![Compression::Zstd, Compression::Lz4]
+    }
+}
+
+// With compression on, payloads start with a flag byte: 0 for raw, 1 for compressed.
+// Small payloads are left raw since they would barely shrink.
+pub fn compress_payload(payload: &[u8], compression: Compression) -> Result<Vec<u8>, io::Error> {
+    if compression == Compression::None {
+        return Ok(payload.to_vec());
+    }
+    let mut out = Vec::with_capacity(payload.len() + 1);
+    if payload.len() < *COMPRESSION_THRESHOLD {
+        out.push(0);
+        out.extend_from_slice(payload);
+        return Ok(out);
+    }
+    out.push(1);
+    match compression {
+        Compression::Zstd => out.extend(zstd::bulk::compress(payload, *ZSTD_LEVEL)?),
+        Compression::Lz4 => out.extend(lz4_flex::compress_prepend_size(payload)),
+        Compression::None => {}
+    }
+    Ok(out)
+}
+
+pub fn decompress_payload(
+    payload: &[u8],
+    compression: Compression,
+    max_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+    if compression == Compression::None {
+        return Ok(payload.to_vec());
+    }
+    let (flag, data) = match payload.split_first() {
+        Some((flag, data)) => (*flag, data),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Compressed frame is missing its flag byte",
+            ))
+        }
+    };
+    match (flag, compression) {
+        (0, _) => Ok(data.to_vec()),
+        (1, Compression::Zstd) => zstd::bulk::decompress(data, max_size),
+        (1, Compression::Lz4) => {
+            // Check the declared size before allocating for it
+            if data.len() < 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Compressed frame is missing its size",
+                ));
+            }
+            let mut size = [0u8; 4];
+            size.copy_from_slice(&data[..4]);
+            let size = u32::from_le_bytes(size) as usize;
+            if size > max_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Decompressed frame of {} bytes exceeds maximum of {}", size, max_size),
+                ));
+            }
+            lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown compression flag {}", flag),
+        )),
+    }
+}
+
+// Every message on the wire is a big-endian u32 length followed by that many bytes of payload.
+pub fn encode_frame(payload: &[u8], max_size: usize) -> Result<Vec<u8>, io::Error> {
+    if payload.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds maximum of {}", payload.len(), max_size),
+        ));
+    }
+    let mut frame = Vec::with_capacity(*FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+pub fn serialize_frame<T: Serialize>(msg: &T, max_size: usize) -> Result<Vec<u8>, io::Error> {
+    let payload = bincode::serialize(msg)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    encode_frame(&payload, max_size)
+}
+
+pub fn deserialize_frame<T: DeserializeOwned>(payload: &[u8]) -> Result<T, io::Error> {
+    bincode::deserialize(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_size: usize,
+}
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::from(*MAX_FRAME_SIZE)
+    }
+    pub fn from(max_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            buffer: vec![],
+            max_size: max_size,
+        }
+    }
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+    // Returns the next complete payload, or None if more bytes are needed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        if self.buffer.len() < *FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; 4];
+        header.copy_from_slice(&self.buffer[..*FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Incoming frame of {} bytes exceeds maximum of {}", len, self.max_size),
+            ));
+        }
+        if self.buffer.len() < *FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let payload = self.buffer[*FRAME_HEADER_SIZE..*FRAME_HEADER_SIZE + len].to_vec();
+        self.buffer.drain(..*FRAME_HEADER_SIZE + len);
+        Ok(Some(payload))
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: FrameDecoder,
+    buffer: Vec<u8>,
+    compression: Compression,
+}
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> FrameReader<R> {
+        FrameReader::from(reader, *MAX_FRAME_SIZE)
+    }
+    pub fn from(reader: R, max_size: usize) -> FrameReader<R> {
+        FrameReader {
+            reader: reader,
+            decoder: FrameDecoder::from(max_size),
+            buffer: vec![0; *READ_BUFFER_SIZE],
+            compression: Compression::None,
+        }
+    }
+    // Takes effect from the next frame, so both sides switch right after the handshake.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    // Reads until a whole frame is buffered. Ok(None) means the peer closed the connection cleanly.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        loop {
+            if let Some(payload) = self.decoder.next_frame()? {
+                return Ok(Some(decompress_payload(
+                    &payload,
+                    self.compression,
+                    self.decoder.max_size,
+                )?));
+            }
+            let n = self.reader.read(&mut self.buffer).await?;
+            if n == 0 {
+                if self.decoder.buffered() > 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Connection closed in the middle of a frame",
+                    ));
+                }
+                return Ok(None);
+            }
+            self.decoder.push(&self.buffer[..n]);
+        }
+    }
+    pub async fn read_message<T: DeserializeOwned>(&mut self) -> Result<Option<T>, io::Error> {
+        match self.read_frame().await? {
+            Some(payload) => Ok(Some(deserialize_frame(&payload)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct FrameWriter<W> {
+    writer: W,
+    max_size: usize,
+    compression: Compression,
+}
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W) -> FrameWriter<W> {
+        FrameWriter::from(writer, *MAX_FRAME_SIZE)
+    }
+    pub fn from(writer: W, max_size: usize) -> FrameWriter<W> {
+        FrameWriter {
+            writer: writer,
+            max_size: max_size,
+            compression: Compression::None,
+        }
+    }
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<(), io::Error> {
+        let payload = compress_payload(payload, self.compression)?;
+        let frame = encode_frame(&payload, self.max_size)?;
+        self.writer.write_all(&frame).await?;
+        self.writer.flush().await
+    }
+    pub async fn write_message<T: Serialize>(&mut self, msg: &T) -> Result<(), io::Error> {
+        let payload = bincode::serialize(msg)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write_frame(&payload).await
+    }
+}
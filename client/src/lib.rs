@@ -1,10 +1,18 @@
 pub mod bitmap;
+pub mod cache;
+pub mod config;
+pub mod frame;
 pub mod lang;
+pub mod local;
 pub mod math;
 pub mod plot;
+pub mod prediction;
 //pub mod renderer;
 //pub mod renderer_opengl;
 pub mod renderer_curses;
+pub mod serving;
+pub mod simulation;
+pub mod snapshot;
 pub mod util;
 pub mod worldgen;
 pub mod net;
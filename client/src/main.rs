@@ -1,18 +1,23 @@
 use async_std::task;
 use crossbeam::channel::unbounded;
-use dimensioner_client_sdl2::net::send_client_data;
+use dimensioner_client_sdl2::config::Config;
+use dimensioner_client_sdl2::local::LocalServer;
+use dimensioner_client_sdl2::net::{Connection, NetError};
 use dimensioner_client_sdl2::plot::plot;
+use dimensioner_client_sdl2::cache::ChunkCache;
+use dimensioner_client_sdl2::prediction::Prediction;
 use dimensioner_client_sdl2::renderer_curses::render_server;
 use dimensioner_client_sdl2::util::{
-    ActionContent, ActionData, ActionType, ClientData, ClientDataType, ClientMsg, MainMsg,
-    RenderMsg,
+    ActionContent, ActionData, ActionType, ChatMessage, ClientData, ClientDataType, ClientMsg,
+    MainMsg, MovementIntent, RenderMsg, ServerError, Subscription,
 };
 use dimensioner_client_sdl2::worldgen::{
-    globegen, worldgen, Coords_i32, Camera, Entity, News, CHUNK_SIZE, TILE_SIZE, WORLD_SIZE,
+    chunk_index, globegen, worldgen, ChunkUpdate, Coords_i32, Camera, Entity, News, CHUNK_SIZE,
+    TILE_SIZE, WORLD_SIZE,
 };
 
-use rand::Rng;
 use rayon::prelude::*;
+use std::collections::HashMap;
 use std::io;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -21,10 +26,22 @@ use std::time::Duration;
 use lazy_static::lazy_static;
 lazy_static! {
     pub static ref PARTITION_SIZE: usize = (*WORLD_SIZE as usize * *WORLD_SIZE as usize) / 16;
-    pub static ref VIEW_DISTANCE: usize = 0;
+    pub static ref VIEW_DISTANCE: usize = 2;
 }
 
 fn main() {
+    // With --local the world runs in this process instead, set up by the server's own flags
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let local = match args.first().map(|a| a.as_str()) {
+        Some("--local") => match Config::from_args(&args[1..]).and_then(|c| LocalServer::start(&c)) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
     let (tx, rx) = unbounded();
     let (tx2, rx2): (
         crossbeam::channel::Sender<MainMsg>,
@@ -47,28 +64,51 @@ fn main() {
         crossbeam::channel::Sender<ClientMsg>,
         crossbeam::channel::Receiver<ClientMsg>,
     ) = unbounded();
+    // Chat typed in the renderer goes out through the net thread, which hands back the lines to show
+    let (tx_chat, rx_chat): (
+        crossbeam::channel::Sender<ChatMessage>,
+        crossbeam::channel::Receiver<ChatMessage>,
+    ) = unbounded();
+    let (tx_chat_lines, rx_chat_lines): (
+        crossbeam::channel::Sender<String>,
+        crossbeam::channel::Receiver<String>,
+    ) = unbounded();
     let rx4_clone = rx4.clone();
     let rx2_clone = rx2.clone();
     let rx3_clone = rx3.clone();
     let tx3_clone = tx3.clone();
     let mut state: Arc<Mutex<Vec<RenderMsg>>> = Arc::new(Mutex::new(vec![]));
-    let mut rng = rand::thread_rng();
-    let random_number = rng.gen_range(0..=100_000);
     let mut player: Arc<Mutex<Entity>> = Arc::new(Mutex::new(Entity::gen_player(
-        random_number,
+        0,
         /*(*TILE_SIZE * *CHUNK_SIZE * *WORLD_SIZE / 2) as f32,
         (*TILE_SIZE * *CHUNK_SIZE * *WORLD_SIZE / 2) as f32,*/
 	0.0,0.0,
         0.0,
     )));
+    // The server allocates our entity index, so nothing runs until we have joined
+    let mut connection = match local {
+        Some(server) => Connection::local(server),
+        None => Connection::new(),
+    };
+    let session = loop {
+        match task::block_on(connection.join(player.lock().unwrap().clone())) {
+            Ok(session) => break session,
+            Err(e) => {
+                eprintln!("Error joining server: {}", e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    };
+    player.lock().unwrap().index = session.entity_index;
     let player_clone = Arc::clone(&player);
-    let player_id = player.lock().unwrap().index;
+    let mut player_id = session.entity_index;
     let mut step = 0;
     let mut step_increment = 1;
     let mut camera = Camera::new();
     let mut vic_world = 0;
     let mut render = false;
     let mut current_action_content = ActionContent::new();
+    let mut current_intent = MovementIntent::new();
     let mut p1 = player.clone();
     let mut p2 = player.clone();
     let mut tx3_c = tx3.clone();
@@ -79,15 +119,23 @@ fn main() {
             player.lock().unwrap().clone(),
             ActionContent::new(),
         ));
-        let _ = tx_c.send(ClientMsg::from(
-            player.lock().unwrap().clone(),
-            current_action_content.clone(),
-        ));
+        let mut msg = ClientMsg::from(player.lock().unwrap().clone(), current_action_content.clone());
+        msg.intent = std::mem::replace(&mut current_intent, MovementIntent::new());
+        let _ = tx_c.send(msg);
         if let Ok(p) = rx4.recv() {
             let mut player_from = p.player;
             player_from.current_action = p.action.action_type.clone();
             current_action_content = p.action.clone();
-            *player.lock().unwrap() = player_from;
+            current_intent = p.intent;
+            // Where the player is and what it has is up to the server
+            let mut player = player.lock().unwrap();
+            player_from.coords = player.coords.clone();
+            player_from.ccoords = player.ccoords.clone();
+            player_from.stats = player.stats.clone();
+            player_from.inventory = player.inventory.clone();
+            player_from.level = player.level;
+            player_from.current_world = player.current_world;
+            *player = player_from;
         }
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     });
@@ -98,12 +146,20 @@ fn main() {
     let s_clone = state.lock().unwrap().clone();
     let tx4_clone = tx4.clone();
     let mut c_i = 0;
-    let mut f_c_plus = Coords_i32::from((-1,-2, -4)); 
+    let mut known_chunks: HashMap<usize, u64> = HashMap::new();
+    let mut subscribed = false;
+    let mut chunk_cache = ChunkCache::new();
+    let mut prediction = Prediction::new();
     thread::spawn(move || loop {
         // Continuously read from the channel until there are no more messages
         let mut latest_message = None;
         let mut latest_message_server = None;
+        let mut intent = MovementIntent::new();
         while let Ok(p) = rx_c.try_recv() {
+            // Keep a step even when later messages are idle
+            if p.intent.is_moving() {
+                intent = p.intent.clone();
+            }
             latest_message = Some(p);
             while let Ok(p) = rx6.try_recv() {
                 latest_message_server = Some(p);
@@ -116,55 +172,118 @@ fn main() {
             let e_i = e.index;
             match latest_message_server {
                 Some(s) => {
-                    player_clone.lock().unwrap().ccoords = s.player.ccoords.clone();
-                    player_clone.lock().unwrap().stats = s.player.stats.clone();
-                    e.ccoords = s.player.ccoords;
+                    // The server's word, with the inputs it has not got to yet on top
+                    let predicted = prediction.reconcile(&s.player, &chunk_cache);
+                    let mut player = player_clone.lock().unwrap();
+                    player.coords = predicted.coords.clone();
+                    player.ccoords = predicted.ccoords.clone();
+                    player.stats = s.player.stats.clone();
+                    player.inventory = s.player.inventory.clone();
+                    player.level = s.player.level;
+                    player.current_world = s.player.current_world;
+                    e.coords = predicted.coords;
+                    e.ccoords = predicted.ccoords;
                     e.stats = s.player.stats;
                 }
                 None => {}
             };
-	    if f_c_plus.x > 1 {
-		f_c_plus.x = -1;
-		f_c_plus.y += 1;
-	    }
-	    if f_c_plus.y > 1 {
-		f_c_plus.x = -1;
-		f_c_plus.y = -2;
-	    }
-	    let mut fetch_coords = e.ccoords.clone();
-	    f_c_plus.x += 1;
-	    fetch_coords.x += f_c_plus.x;
-	    fetch_coords.y += f_c_plus.y;
+            // Moves show up right away; ones sent too soon after the last would be turned down
+            let sequence = match prediction.input(&e, &intent, &chunk_cache) {
+                Some((sequence, predicted)) => {
+                    let mut player = player_clone.lock().unwrap();
+                    player.coords = predicted.coords.clone();
+                    player.ccoords = predicted.ccoords.clone();
+                    e.coords = predicted.coords;
+                    e.ccoords = predicted.ccoords;
+                    sequence
+                }
+                None => {
+                    intent = MovementIntent::new();
+                    0
+                }
+            };
+            // A reconnect after the server let go of our entity rejoins under a new index
+            if let Some(session) = connection.session() {
+                player_id = session.entity_index;
+            }
+            // The view around the player is pushed by the server; requests only carry our own chunk
+            let mut updates = vec![];
+            if !subscribed {
+                let subscription = Subscription::from(
+                    ClientDataType::Radius(*VIEW_DISTANCE as i32),
+                    e.ccoords.clone(),
+                    Some(player_id),
+                );
+                match task::block_on(connection.subscribe(subscription)) {
+                    Ok(u) => {
+                        subscribed = true;
+                        updates.extend(u);
+                    }
+                    Err(e) => eprintln!("Error subscribing to chunks: {}", e),
+                }
+            }
+            let data_type = ClientDataType::Chunk;
+            let view_known_chunks = data_type
+                .chunk_coords(&e.ccoords)
+                .iter()
+                .filter_map(|c| chunk_index(c))
+                .filter_map(|i| known_chunks.get(&i).map(|h| (i, *h)))
+                .collect();
             let s = ClientData {
-		ccoords: fetch_coords, 
+		ccoords: e.ccoords.clone(),
                 entity: e,
                 action: p.action.clone(),
-                data_type: ClientDataType::Chunk,
+                data_type: data_type,
+                known_chunks: view_known_chunks,
+                intent: intent,
+                sequence: sequence,
+                // Filled in from the session by the connection
+                session: 0,
             };
             let a = ActionData {
                 entity: p.player.clone(),
                 action: p.action.action_type.clone(),
             };
-            let result = task::block_on(send_client_data(s));
+            let result = task::block_on(connection.request(&s));
             match result {
-                Ok(chunk) => {
-                    if let Some(chunks) = chunk {
-                        for c in chunks {
-                            c.entities.clone().into_iter().find(|e| {
-                                if e.index == player_id {
-                                    tx6.send(ClientMsg::from(e.clone(), ActionContent::new()));
-                                }
-                                false
-                            });
-                            state_clone_clone
-                                .lock()
-                                .unwrap()
-                                .push(RenderMsg::from(c.clone(), c.inquire_news()));
-                        }
-                    }
-                }
+                Ok(u) => updates.extend(u),
+                Err(NetError::Server(ServerError::OutOfBounds(_))) => {}
                 Err(e) => eprintln!("Error fetching chunk: {}", e),
             };
+            updates.extend(connection.take_pushed());
+            for message in rx_chat.try_iter() {
+                let line = match task::block_on(connection.chat(message.channel, &message.text)) {
+                    Ok(sent) => sent.to_string(),
+                    Err(e) => format!("Chat failed: {}", e),
+                };
+                let _ = tx_chat_lines.send(line);
+            }
+            for message in connection.take_chat() {
+                let _ = tx_chat_lines.send(message.to_string());
+            }
+            let tick = connection.tick();
+            for u in updates {
+                u.entities().clone().into_iter().find(|e| {
+                    if e.index == player_id {
+                        tx6.send(ClientMsg::from(e.clone(), ActionContent::new()));
+                    }
+                    false
+                });
+                if chunk_cache.apply(&u, tick) {
+                    known_chunks.insert(u.index(), u.hash());
+                }
+                let news = match &u {
+                    ChunkUpdate::Full(c) => c.inquire_news(),
+                    ChunkUpdate::Delta(_) => News::new(),
+                };
+                state_clone_clone
+                    .lock()
+                    .unwrap()
+                    .push(RenderMsg::from(u, news, tick));
+            }
+            chunk_cache.retain_around(&s.ccoords, *VIEW_DISTANCE as i32 + 1);
+            // The server only sends deltas against chunks that are still here to apply them to
+            known_chunks.retain(|i, _| chunk_cache.contains(*i));
         }
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     });
@@ -175,8 +294,8 @@ fn main() {
     let mut p_server_local: Option<Entity> = None;
     let mut state_clone = Arc::clone(&state);
     thread::spawn(move || loop {
-        let _ = tx.send(state.lock().unwrap().clone());
-        state.lock().unwrap().clear();
+        // Take the batch under one lock so no update pushed in between is lost
+        let _ = tx.send(std::mem::take(&mut *state.lock().unwrap()));
         step += step_increment;
         if let Some(ref p) = p {
             if let Some(ref p_s) = p_server_local {}
@@ -194,5 +313,5 @@ fn main() {
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
         partition += 1;
     });
-    render_server(&tx2, &rx, &tx4, &rx3);
+    render_server(&tx2, &rx, &tx4, &rx3, &tx_chat, &rx_chat_lines);
 }
@@ -13,3 +13,9 @@ pub fn dist_f32_f32(c1: &Coords_f32, c2: &Coords_f32) -> i32 {
 pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + t * (b - a)
 }
+// Turns the short way round, angles are in radians.
+pub fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    let tau = std::f32::consts::TAU;
+    let delta = (b - a + tau / 2.0).rem_euclid(tau) - tau / 2.0;
+    lerp(a, a + delta, t)
+}
@@ -1,63 +1,532 @@
-use crate::worldgen::{Chunk, Entity};
-use crate::util::{ActionData, ClientData};
-use reqwest::{Client, Error};
-use serde::{Deserialize, Serialize};
-use bincode;
+use crate::frame::{FrameReader, FrameWriter};
+use crate::local::{LocalPeer, LocalServer};
+use crate::util::{
+    Capability, ChatChannel, ChatMessage, ClientData, ClientMessage, Handshake, ServerError,
+    ServerMessage, Session, Subscription,
+};
+use crate::worldgen::{ChunkUpdate, Entity};
+use lazy_static::lazy_static;
+use std::cmp::min;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::io::{self, AsyncWriteExt, AsyncReadExt};
-use tokio::task;
-use std::sync::Arc;
 
+lazy_static! {
+    pub static ref SERVER_ADDRESS: String = "127.0.0.1:3000".to_string();
+    pub static ref RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+    pub static ref RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+    // Chat nobody takes is dropped oldest first past this many messages
+    pub static ref CHAT_BACKLOG: usize = 256;
+}
 
-pub async fn send_client_data(client_data: ClientData) -> Result<Option<Vec<Chunk>>, io::Error> {
-    // Serialize the ClientData to binary format
-    let serialized_data = bincode::serialize(&client_data).expect("Failed to serialize ClientData");
-    // Connect to the server
-    let stream = TcpStream::connect("127.0.0.1:3000").await?;
-
-    // Split the TcpStream into reader and writer
-    let (mut reader, mut writer) = tokio::io::split(stream);
-
-    // Spawn a task for writing the client data
-    let write_task = task::spawn(async move {
-        writer.write_all(&serialized_data).await?;
-        writer.flush().await?;
-        Ok::<Option<Vec<Chunk>>, io::Error>(None)
-    });
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Server(ServerError),
+}
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "{}", e),
+            NetError::Server(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> NetError {
+        NetError::Io(e)
+    }
+}
+impl From<ServerError> for NetError {
+    fn from(e: ServerError) -> NetError {
+        NetError::Server(e)
+    }
+}
 
-    // Spawn a task for reading the response
-    let read_task = task::spawn(async move {
-        let mut buffer = vec![0; 65536]; // Allocate a buffer for the incoming response
-	let mut chunk = None;
-        match reader.read(&mut buffer).await {
-            Ok(0) => {
-                eprintln!("Server closed the connection.");
-            }
-            Ok(n) => {
-                let response: Result<Vec<Chunk>, _> = bincode::deserialize(&buffer[..n]);
-                match response {
-                    Ok(data) => {
-                        //println!("Received response: {:?}", data);
-			chunk = Some(data);
-                    }
-                    Err(e) => {
-                   //     eprintln!("Failed to parse server response with error {}", e);
-                    }
+// A long-lived connection to the server. Requests may be pipelined with send() and their
+// responses collected in order with recv(). A dropped link is reopened on the next call,
+// backing off exponentially while the server stays unreachable. A local connection talks to a
+// server running in this process instead, and never drops.
+pub struct Connection {
+    pub addr: String,
+    pub capabilities: Vec<Capability>,
+    reader: Option<FrameReader<OwnedReadHalf>>,
+    writer: Option<FrameWriter<OwnedWriteHalf>>,
+    in_flight: usize,
+    backoff: Duration,
+    next_attempt: Instant,
+    subscription: Option<Subscription>,
+    pushed: Vec<ChunkUpdate>,
+    chat: Vec<ChatMessage>,
+    // Server tick of the newest updates received
+    tick: u64,
+    joined_as: Option<Entity>,
+    session: Option<Session>,
+    local: Option<LocalPeer>,
+}
+impl Connection {
+    pub fn new() -> Connection {
+        Connection::from(&SERVER_ADDRESS)
+    }
+    pub fn from(addr: &str) -> Connection {
+        Connection {
+            addr: addr.to_string(),
+            capabilities: vec![],
+            reader: None,
+            writer: None,
+            in_flight: 0,
+            backoff: *RECONNECT_BACKOFF_MIN,
+            next_attempt: Instant::now(),
+            subscription: None,
+            pushed: vec![],
+            chat: vec![],
+            tick: 0,
+            joined_as: None,
+            session: None,
+            local: None,
+        }
+    }
+    pub fn local(server: LocalServer) -> Connection {
+        Connection {
+            capabilities: Capability::supported(),
+            local: Some(LocalPeer::from(server)),
+            ..Connection::from("local")
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        self.writer.is_some() || self.local.is_some()
+    }
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+    async fn ensure_connected(&mut self) -> Result<(), NetError> {
+        if self.is_connected() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now < self.next_attempt {
+            return Err(NetError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!(
+                    "Not connected to {}, retrying in {:?}",
+                    self.addr,
+                    self.next_attempt - now
+                ),
+            )));
+        }
+        match self.open().await {
+            Ok(()) => {
+                self.backoff = *RECONNECT_BACKOFF_MIN;
+                Ok(())
+            }
+            Err(e) => {
+                self.reader = None;
+                self.writer = None;
+                self.next_attempt = now + self.backoff;
+                self.backoff = min(self.backoff * 2, *RECONNECT_BACKOFF_MAX);
+                Err(e)
+            }
+        }
+    }
+    async fn open(&mut self) -> Result<(), NetError> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let _ = stream.set_nodelay(true);
+        let (reader, writer) = stream.into_split();
+        let mut reader = FrameReader::new(reader);
+        let mut writer = FrameWriter::new(writer);
+        writer
+            .write_message(&ClientMessage::Handshake(Handshake::new()))
+            .await?;
+        match reader.read_message::<ServerMessage>().await? {
+            Some(ServerMessage::Handshake(handshake)) => {
+                reader.set_compression(handshake.compression());
+                writer.set_compression(handshake.compression());
+                self.capabilities = handshake.capabilities;
+            }
+            Some(ServerMessage::Error(e)) => return Err(NetError::Server(e)),
+            Some(_) => {
+                return Err(NetError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Expected a handshake from the server",
+                )))
+            }
+            None => {
+                return Err(NetError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed the connection during handshake",
+                )))
+            }
+        }
+        // A new link starts without a session or subscriptions, so restore the ones we had.
+        // The old session is tried first so we get our entity back if the server still has it.
+        let mut resumed = false;
+        if let Some(session) = self.session.clone() {
+            writer
+                .write_message(&ClientMessage::Resume(session))
+                .await?;
+            match read_reply(
+                &mut reader,
+                &mut self.pushed,
+                &mut self.chat,
+                &mut self.tick,
+            )
+            .await?
+            {
+                ServerMessage::Joined(session) => {
+                    self.rejoined(session);
+                    resumed = true;
+                }
+                ServerMessage::Error(ServerError::InvalidSession) => {}
+                ServerMessage::Error(e) => return Err(NetError::Server(e)),
+                _ => {
+                    return Err(NetError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected a session from the server",
+                    )))
                 }
             }
+        }
+        if let Some(entity) = self.joined_as.clone().filter(|_| !resumed) {
+            writer.write_message(&ClientMessage::Join(entity)).await?;
+            match read_reply(
+                &mut reader,
+                &mut self.pushed,
+                &mut self.chat,
+                &mut self.tick,
+            )
+            .await?
+            {
+                ServerMessage::Joined(session) => self.rejoined(session),
+                ServerMessage::Error(e) => return Err(NetError::Server(e)),
+                _ => {
+                    return Err(NetError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected a session from the server",
+                    )))
+                }
+            }
+        }
+        if let Some(subscription) = self.subscription.clone() {
+            writer
+                .write_message(&ClientMessage::Subscribe(subscription))
+                .await?;
+            match read_reply(
+                &mut reader,
+                &mut self.pushed,
+                &mut self.chat,
+                &mut self.tick,
+            )
+            .await?
+            {
+                ServerMessage::ChunkUpdates(tick, updates) => {
+                    self.tick = self.tick.max(tick);
+                    self.pushed.extend(updates);
+                }
+                ServerMessage::Error(e) => return Err(NetError::Server(e)),
+                _ => {
+                    return Err(NetError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected the subscribed area from the server",
+                    )))
+                }
+            }
+        }
+        self.reader = Some(reader);
+        self.writer = Some(writer);
+        self.in_flight = 0;
+        Ok(())
+    }
+    pub fn disconnect(&mut self) {
+        self.reader = None;
+        self.writer = None;
+        self.in_flight = 0;
+        self.next_attempt = Instant::now();
+    }
+    async fn send_message(&mut self, message: &ClientMessage) -> Result<(), NetError> {
+        self.ensure_connected().await?;
+        if let Some(local) = self.local.as_mut() {
+            local.send(message.clone());
+            self.in_flight += 1;
+            return Ok(());
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("Writer missing on open connection");
+        match writer.write_message(message).await {
+            Ok(()) => {
+                self.in_flight += 1;
+                Ok(())
+            }
             Err(e) => {
-                eprintln!("Error reading from server: {}", e);
+                self.disconnect();
+                Err(NetError::Io(e))
             }
         }
-        Ok::<Option<Vec<Chunk>>, io::Error>(chunk)
-    });
+    }
+    // Data always goes out as the entity of the current session.
+    pub async fn send(&mut self, client_data: &ClientData) -> Result<(), NetError> {
+        // Reconnecting first, as that may change the session
+        self.ensure_connected().await?;
+        let mut client_data = client_data.clone();
+        if let Some(session) = &self.session {
+            client_data.session = session.token;
+            client_data.entity.index = session.entity_index;
+        }
+        self.send_message(&ClientMessage::Data(client_data)).await
+    }
+    async fn read_message(&mut self) -> Result<ServerMessage, NetError> {
+        if let Some(local) = self.local.as_mut() {
+            return local.recv().await.ok_or(NetError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The local server has stopped",
+            )));
+        }
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => {
+                return Err(NetError::Io(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Not connected",
+                )))
+            }
+        };
+        match reader.read_message::<ServerMessage>().await {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => {
+                self.disconnect();
+                Err(NetError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed the connection",
+                )))
+            }
+            Err(e) => {
+                self.disconnect();
+                Err(NetError::Io(e))
+            }
+        }
+    }
+    // Pushed updates and chat read while waiting are kept for take_pushed() and take_chat().
+    async fn recv_reply(&mut self) -> Result<ServerMessage, NetError> {
+        if self.in_flight == 0 {
+            return Err(NetError::Io(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No request awaiting a response",
+            )));
+        }
+        loop {
+            let message = self.read_message().await?;
+            if let Some(message) =
+                queue_unprompted(message, &mut self.pushed, &mut self.chat, &mut self.tick)
+            {
+                self.in_flight -= 1;
+                return Ok(message);
+            }
+        }
+    }
+    pub async fn recv(&mut self) -> Result<Vec<ChunkUpdate>, NetError> {
+        match self.recv_reply().await? {
+            ServerMessage::Chunks(chunks) => {
+                Ok(chunks.into_iter().map(ChunkUpdate::Full).collect())
+            }
+            ServerMessage::ChunkUpdates(tick, updates) => {
+                self.tick = self.tick.max(tick);
+                Ok(updates)
+            }
+            ServerMessage::Error(e) => Err(NetError::Server(e)),
+            _ => Err(NetError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected message from the server",
+            ))),
+        }
+    }
+    pub async fn request(
+        &mut self,
+        client_data: &ClientData,
+    ) -> Result<Vec<ChunkUpdate>, NetError> {
+        self.send(client_data).await?;
+        self.recv().await
+    }
+    // Per-request server errors are returned in place so one bad request does not
+    // discard the rest of the batch.
+    pub async fn pipeline(
+        &mut self,
+        requests: &[ClientData],
+    ) -> Result<Vec<Result<Vec<ChunkUpdate>, ServerError>>, NetError> {
+        for client_data in requests {
+            self.send(client_data).await?;
+        }
+        let mut responses = vec![];
+        while self.in_flight > 0 {
+            match self.recv().await {
+                Ok(updates) => responses.push(Ok(updates)),
+                Err(NetError::Server(e)) => responses.push(Err(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(responses)
+    }
+    // The subscription is kept and renewed on every reconnect. Returns the current state of the area.
+    pub async fn subscribe(
+        &mut self,
+        subscription: Subscription,
+    ) -> Result<Vec<ChunkUpdate>, NetError> {
+        self.subscription = Some(subscription.clone());
+        if !self.is_connected() {
+            // Connecting sends the subscription and queues the answer as pushed updates
+            self.ensure_connected().await?;
+            return Ok(self.take_pushed());
+        }
+        self.send_message(&ClientMessage::Subscribe(subscription))
+            .await?;
+        self.recv().await
+    }
+    pub async fn unsubscribe(&mut self) -> Result<(), NetError> {
+        self.subscription = None;
+        if !self.is_connected() {
+            return Ok(());
+        }
+        self.send_message(&ClientMessage::Unsubscribe).await?;
+        self.recv().await.map(|_| ())
+    }
+    // Spawns entity as this client's player. After a reconnect the connection resumes the session,
+    // or rejoins with it under a new entity index once the server has let the old one go.
+    pub async fn join(&mut self, entity: Entity) -> Result<Session, NetError> {
+        self.joined_as = Some(entity.clone());
+        if !self.is_connected() {
+            self.ensure_connected().await?;
+        } else {
+            self.send_message(&ClientMessage::Join(entity)).await?;
+            match self.recv_reply().await? {
+                ServerMessage::Joined(session) => self.rejoined(session),
+                ServerMessage::Error(e) => return Err(NetError::Server(e)),
+                _ => {
+                    return Err(NetError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected a session from the server",
+                    )))
+                }
+            }
+        }
+        self.session
+            .clone()
+            .ok_or(NetError::Server(ServerError::JoinRequired))
+    }
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+    fn rejoined(&mut self, session: Session) {
+        let old_index = self.session.as_ref().map(|s| s.entity_index);
+        if let Some(subscription) = self.subscription.as_mut() {
+            if subscription.follow.is_some() && subscription.follow == old_index {
+                subscription.follow = Some(session.entity_index);
+            }
+        }
+        self.session = Some(session);
+    }
+    pub fn take_pushed(&mut self) -> Vec<ChunkUpdate> {
+        std::mem::take(&mut self.pushed)
+    }
+    // Server tick of the newest updates returned or queued so far.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+    // Waits for the next push when none are queued. Only valid with no requests in flight.
+    pub async fn recv_pushed(&mut self) -> Result<Vec<ChunkUpdate>, NetError> {
+        if !self.pushed.is_empty() {
+            return Ok(self.take_pushed());
+        }
+        self.ensure_connected().await?;
+        loop {
+            match self.read_message().await? {
+                ServerMessage::Push(tick, updates) => {
+                    self.tick = self.tick.max(tick);
+                    return Ok(updates);
+                }
+                ServerMessage::Chat(message) => queue_chat(&mut self.chat, message),
+                ServerMessage::Error(e) => return Err(NetError::Server(e)),
+                _ => {
+                    return Err(NetError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Expected a pushed update from the server",
+                    )))
+                }
+            }
+        }
+    }
+    // Returns the message as the server delivered it, with our name and the channel it went to.
+    pub async fn chat(
+        &mut self,
+        channel: ChatChannel,
+        text: &str,
+    ) -> Result<ChatMessage, NetError> {
+        self.send_message(&ClientMessage::Chat(ChatMessage::from(
+            channel,
+            text.to_string(),
+        )))
+        .await?;
+        match self.recv_reply().await? {
+            ServerMessage::ChatSent(message) => Ok(message),
+            ServerMessage::Error(e) => Err(NetError::Server(e)),
+            _ => Err(NetError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected the sent chat message from the server",
+            ))),
+        }
+    }
+    pub fn take_chat(&mut self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.chat)
+    }
+}
+
+// Keeps messages the server sends on its own, returning anything that answers a request.
+fn queue_unprompted(
+    message: ServerMessage,
+    pushed: &mut Vec<ChunkUpdate>,
+    chat: &mut Vec<ChatMessage>,
+    tick: &mut u64,
+) -> Option<ServerMessage> {
+    match message {
+        ServerMessage::Push(t, updates) => {
+            *tick = (*tick).max(t);
+            pushed.extend(updates);
+        }
+        ServerMessage::Chat(message) => queue_chat(chat, message),
+        message => return Some(message),
+    }
+    None
+}
+
+fn queue_chat(chat: &mut Vec<ChatMessage>, message: ChatMessage) {
+    chat.push(message);
+    if chat.len() > *CHAT_BACKLOG {
+        let excess = chat.len() - *CHAT_BACKLOG;
+        chat.drain(..excess);
+    }
+}
 
-    // Await both tasks
-    let _ = write_task.await?;
-    let chunk = read_task.await?;
-    match chunk {
-	Ok(Some(c)) => Ok(Some(c)),
-	Ok(None) => Ok(None),
-	Err(e) => {eprintln!("{}", e); Ok(None)}
+// Reads the answer to a request sent during open(), queueing pushes and chat that arrive first.
+async fn read_reply(
+    reader: &mut FrameReader<OwnedReadHalf>,
+    pushed: &mut Vec<ChunkUpdate>,
+    chat: &mut Vec<ChatMessage>,
+    tick: &mut u64,
+) -> Result<ServerMessage, NetError> {
+    loop {
+        match reader.read_message::<ServerMessage>().await? {
+            Some(message) => {
+                if let Some(message) = queue_unprompted(message, pushed, chat, tick) {
+                    return Ok(message);
+                }
+            }
+            None => {
+                return Err(NetError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Server closed the connection",
+                )))
+            }
+        }
     }
 }
@@ -0,0 +1,78 @@
+use crate::cache::ChunkCache;
+use crate::util::{step_entity, MovementIntent, MOVE_INTERVAL};
+use crate::worldgen::{Coords_i32, Entity, CHUNK_SIZE, TILE_SIZE};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // A little slower than the server allows, so jitter on the way does not get inputs turned down
+    pub static ref INPUT_INTERVAL: Duration = *MOVE_INTERVAL * 5 / 4;
+    // Past this many unanswered inputs the server is not keeping up and guessing further is pointless
+    pub static ref MAX_PENDING_INPUTS: usize = 64;
+}
+
+// Moves the player as soon as a key is pressed instead of a round trip later. Every input is
+// numbered; when the server's entity comes back, the inputs it has applied are dropped and the
+// rest are replayed on top of it.
+pub struct Prediction {
+    sequence: u64,
+    pending: VecDeque<(u64, MovementIntent)>,
+    last_input: Option<Instant>,
+}
+impl Prediction {
+    pub fn new() -> Prediction {
+        Prediction {
+            sequence: 0,
+            pending: VecDeque::new(),
+            last_input: None,
+        }
+    }
+    // The number to send the intent with and where it takes the entity. None if it comes too
+    // soon after the last one, in which case it is not sent at all.
+    pub fn input(
+        &mut self,
+        entity: &Entity,
+        intent: &MovementIntent,
+        chunks: &ChunkCache,
+    ) -> Option<(u64, Entity)> {
+        if !intent.is_moving()
+            || self
+                .last_input
+                .map_or(false, |t| t.elapsed() < *INPUT_INTERVAL)
+        {
+            return None;
+        }
+        self.last_input = Some(Instant::now());
+        self.sequence += 1;
+        self.pending.push_back((self.sequence, intent.clone()));
+        if self.pending.len() > *MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        Some((self.sequence, predict(entity, intent, chunks)))
+    }
+    // The server's entity with every input it has not applied yet replayed on top.
+    pub fn reconcile(&mut self, server: &Entity, chunks: &ChunkCache) -> Entity {
+        while let Some((sequence, _)) = self.pending.front() {
+            if *sequence > server.input_sequence {
+                break;
+            }
+            self.pending.pop_front();
+        }
+        self.pending
+            .iter()
+            .fold(server.clone(), |e, (_, intent)| predict(&e, intent, chunks))
+    }
+}
+
+fn predict(entity: &Entity, intent: &MovementIntent, chunks: &ChunkCache) -> Entity {
+    let mut entity = step_entity(entity, intent, |x, y| chunks.fetch_tile(x, y));
+    let tile = *TILE_SIZE as f32;
+    let size = *CHUNK_SIZE as i32;
+    entity.ccoords = Coords_i32::from((
+        ((entity.coords.x.as_f32() / tile).floor() as i32).div_euclid(size),
+        ((entity.coords.y.as_f32() / tile).floor() as i32).div_euclid(size),
+        0,
+    ));
+    entity
+}
@@ -1,9 +1,13 @@
+use crate::cache::ChunkCache;
 use crate::math::{dist_f32_i32, lerp};
 use crate::ui::*;
-use crate::util::{ActionContent, ActionType, ClientMsg, MainMsg, RenderMsg};
+use crate::util::{
+    ActionContent, ActionType, ChatChannel, ChatMessage, ClientMsg, MainMsg, MovementIntent,
+    RenderMsg,
+};
 use crate::worldgen::{
-    Camera, Chunk, Class, Coords_f32, Coords_i32, DialogueTree, Entity, EntityType, Faction,
-    HashableF32, Stats, Tile, TileType, CHUNK_SIZE, TILE_SIZE, WORLD_SIZE,
+    Camera, Chunk, ChunkUpdate, Class, Coords_f32, Coords_i32, DialogueTree, Entity, EntityType,
+    Faction, HashableF32, Stats, Tile, TileType, CHUNK_SIZE, TILE_SIZE, WORLD_SIZE,
 };
 use crossbeam::channel::unbounded;
 use lazy_static::lazy_static;
@@ -21,6 +25,10 @@ lazy_static! {
     pub static ref WINDOW_HEIGHT: i32 = 24;
     pub static ref HUD_WIDTH: i32 = 24;
     pub static ref HUD_HEIGHT: i32 = 80;
+    // Chat lines shown above the player sheet, and how many are kept
+    pub static ref CHAT_LINES: usize = 6;
+    pub static ref CHAT_HISTORY: usize = 100;
+    pub static ref CHAT_RADIUS: i32 = 2;
 }
 
 #[derive(Clone, Debug)]
@@ -44,6 +52,8 @@ pub fn render_server(
     rx: &crossbeam::channel::Receiver<Vec<RenderMsg>>,
     sx_client: &crossbeam::channel::Sender<ClientMsg>,
     rx_client: &crossbeam::channel::Receiver<ClientMsg>,
+    sx_chat: &crossbeam::channel::Sender<ChatMessage>,
+    rx_chat: &crossbeam::channel::Receiver<String>,
 ) {
     let mut vicinity_box = VicinityBox::new();
     let mut camera = Camera::new();
@@ -51,7 +61,7 @@ pub fn render_server(
     camera.coords.y = HashableF32(-12.0 * *TILE_SIZE as f32);
     let mut last_frame_time = Instant::now();
     let mut r: Option<Vec<RenderMsg>> = None;
-    let mut current_chunks: Vec<Chunk> = vec![];
+    let mut cache = ChunkCache::new();
     let mut player: Option<Entity> = None;
     let mut ui_state_entities: HashMap<i32, Entity> = HashMap::new();
     let mut ui_state_tiles: HashMap<i32, Tile> = HashMap::new();
@@ -76,6 +86,7 @@ pub fn render_server(
     let mut highlighted_tile: Option<Tile> = None;
     let mut character_menu_show = false;
     let mut dialogue = false;
+    let mut jump = false;
     let mut current_dialogue_tree: Option<DialogueTree> = None;
     let mut character_menu_nodes = vec!["Stats", "Skills", "Inventory", "Done"];
     loop {
@@ -106,7 +117,7 @@ pub fn render_server(
     let mut name = "".to_string();
     let mut chosen_class = Class::Detective;
     let mut selected_index = 0;
-    let mut last_message: String = "You have embarked.".to_string();
+    let mut chat_log: Vec<String> = vec!["You have embarked.".to_string()];
     let mut settlement_message : String = "Nowhere".to_string();
 
     loop {
@@ -210,23 +221,10 @@ pub fn render_server(
         }
         for message in &r {
             for c in message {
-                current_chunks.retain(|c2| c2.hash != c.chunk.hash);
-            }
-        }
-        for message in &r {
-            for c in message {
-                // Remove any chunk with the same hash from current_chunks
-                if let Some(pos) = current_chunks
-                    .iter()
-                    .position(|c2| c.chunk.index == c2.index)
-                {
-                    current_chunks.remove(pos);
-                }
-                // Add the new chunk to current_chunks
-                current_chunks.push(c.chunk.clone());
+                cache.apply(&c.update, c.tick);
             }
         }
-        for chunk in &current_chunks {
+        for chunk in cache.chunks() {
             for (i, t) in chunk.tiles.iter().enumerate() {
                 let mut s = " ";
                 match t.ttype {
@@ -293,6 +291,8 @@ pub fn render_server(
                 }
             }
             for e in &chunk.entities {
+                // Others move smoothly between the states the server sent
+                let e = &cache.interpolate(e, now);
                 if let Some(ref player) = player {
                     if e.index == player.index {
 			if player.ccoords == chunk.coords {
@@ -365,7 +365,19 @@ pub fn render_server(
             );
         }
 
-        window.mvaddstr(*WINDOW_HEIGHT / 2 - 1, 0, &last_message);
+        chat_log.extend(rx_chat.try_iter());
+        if chat_log.len() > *CHAT_HISTORY {
+            chat_log.drain(..chat_log.len() - *CHAT_HISTORY);
+        }
+        let shown = &chat_log[chat_log.len().saturating_sub(*CHAT_LINES)..];
+        for (i, line) in shown.iter().enumerate() {
+            let line: String = line.chars().take(*HUD_WIDTH as usize - 1).collect();
+            window.mvaddstr(
+                *WINDOW_HEIGHT / 2 - shown.len() as i32 + i as i32,
+                0,
+                &line,
+            );
+        }
         window.mvaddstr(0, *WINDOW_WIDTH / 2, &settlement_message);
         window.mvaddstr(*WINDOW_HEIGHT - 4, 0, get_time_as_string());
         if let Ok(rm) = rx_client.recv() {
@@ -401,6 +413,9 @@ pub fn render_server(
                 ));
             }
 
+            // Movement is only asked for; the server moves the player. Shift runs, and space
+            // makes the next step a jump.
+            let mut intent = MovementIntent::new();
             match window.getch() {
                 Some(Input::Character('q')) => {
                     // Quit the application
@@ -408,14 +423,16 @@ pub fn render_server(
                     break;
                 }
                 Some(Input::Character(c)) => {
-                    if c == 'w' {
-                        m.coords.y -= HashableF32(*TILE_SIZE as f32);
-                    } else if c == 'a' {
-                        m.coords.x -= HashableF32(*TILE_SIZE as f32);
-                    } else if c == 's' {
-                        m.coords.y += HashableF32(*TILE_SIZE as f32);
-                    } else if c == 'd' {
-                        m.coords.x += HashableF32(*TILE_SIZE as f32);
+                    if c == 'w' || c == 'W' {
+                        intent = MovementIntent::from(0, -1, c == 'W', jump);
+                    } else if c == 'a' || c == 'A' {
+                        intent = MovementIntent::from(-1, 0, c == 'A', jump);
+                    } else if c == 's' || c == 'S' {
+                        intent = MovementIntent::from(0, 1, c == 'S', jump);
+                    } else if c == 'd' || c == 'D' {
+                        intent = MovementIntent::from(1, 0, c == 'D', jump);
+                    } else if c == ' ' {
+                        jump = true;
                     } else if c == 'h' {
                         vicinity_box.coords.x -= HashableF32(*TILE_SIZE as f32);
                     } else if c == 'j' {
@@ -426,6 +443,10 @@ pub fn render_server(
                         vicinity_box.coords.x += HashableF32(*TILE_SIZE as f32);
                     } else if c == 'm' {
                         character_menu_show = true;
+                    } else if c == 't' {
+                        if let Some(message) = compose_chat(&window, &m) {
+                            let _ = sx_chat.send(message);
+                        }
                     } else if c == 'e' {
                         if let Some(ref mut highlighted_entity) = highlighted_entity {
                             current_dialogue_tree = highlighted_entity.dialogue.clone();
@@ -511,7 +532,12 @@ pub fn render_server(
                 }
             }
 
-            let _ = sx_client.send(ClientMsg::from(m.clone(), ActionContent::new()));
+            if intent.is_moving() {
+                jump = false;
+            }
+            let mut msg = ClientMsg::from(m.clone(), ActionContent::new());
+            msg.intent = intent;
+            let _ = sx_client.send(msg);
         } else if let Some(ref player) = player {
             let _ = sx_client.send(ClientMsg::from(player.clone(), ActionContent::new()));
         }
@@ -587,6 +613,48 @@ fn parse_dialogue(window: &Window, player: &Entity, stats: &mut Stats, current_d
         }
     }
 }
+// Tab switches channel, enter sends and escape gives up.
+fn compose_chat(window: &Window, player: &Entity) -> Option<ChatMessage> {
+    let channels = [
+        ("Global", ChatChannel::Global),
+        ("Local", ChatChannel::Proximity(*CHAT_RADIUS)),
+        ("Faction", ChatChannel::Faction(player.alignment.faction.clone())),
+    ];
+    let mut channel = 0;
+    let mut text = String::new();
+    window.nodelay(false);
+    curs_set(1);
+    let message = loop {
+        window.mv(*WINDOW_HEIGHT - 1, 0);
+        window.clrtoeol();
+        window.mvaddstr(
+            *WINDOW_HEIGHT - 1,
+            0,
+            format!("{}> {}", channels[channel].0, text),
+        );
+        window.refresh();
+        match window.getch() {
+            Some(Input::Character('\n')) => {
+                if text.trim().is_empty() {
+                    break None;
+                }
+                break Some(ChatMessage::from(channels[channel].1.clone(), text));
+            }
+            Some(Input::Character('\u{1b}')) => break None,
+            Some(Input::Character('\t')) => channel = (channel + 1) % channels.len(),
+            Some(Input::KeyBackspace)
+            | Some(Input::Character('\u{7f}'))
+            | Some(Input::Character('\u{8}')) => {
+                text.pop();
+            }
+            Some(Input::Character(c)) => text.push(c),
+            _ => {}
+        }
+    };
+    curs_set(0);
+    window.nodelay(true);
+    message
+}
 fn get_time_as_string() -> String {
     return "1.1.2080".to_string();
 }
@@ -1,16 +1,26 @@
-use crate::worldgen::{Camera, Entity, Chunk, Coords_i32, News, HashableF32};
+use crate::frame::Compression;
+use crate::worldgen::{
+    dimensions, Camera, Chunk, ChunkUpdate, Coords_i32, Dimensions, Entity, Faction, HashableF32,
+    News, Tile, TileType, TILE_SIZE,
+};
+use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct RenderMsg {
-    pub chunk: Chunk,
+    pub update: ChunkUpdate,
     pub news: News,
+    // Server tick of the update
+    pub tick: u64,
 }
 impl RenderMsg {
-    pub fn from(chunk: Chunk, news: News) -> RenderMsg {
+    pub fn from(update: ChunkUpdate, news: News, tick: u64) -> RenderMsg {
         RenderMsg {
-            chunk: chunk,
+            update: update,
             news: news,
+            tick: tick,
         }
     }
 }
@@ -34,20 +44,116 @@ impl MainMsg {
 pub struct ClientMsg {
     pub player: Entity,
     pub action: ActionContent,
+    pub intent: MovementIntent,
 }
 impl ClientMsg{
     pub fn from(player: Entity, action: ActionContent) -> ClientMsg{
         ClientMsg {
             player: player,
 	    action: action,
+	    intent: MovementIntent::new(),
         }
     }
 }
 
+// What the player wants to do this step. The server decides where that actually gets them.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct MovementIntent {
+    pub dx: i8,
+    pub dy: i8,
+    pub run: bool,
+    pub jump: bool,
+}
+impl MovementIntent {
+    pub fn new() -> MovementIntent {
+	MovementIntent {
+	    dx: 0,
+	    dy: 0,
+	    run: false,
+	    jump: false,
+	}
+    }
+    pub fn from(dx: i8, dy: i8, run: bool, jump: bool) -> MovementIntent {
+	MovementIntent {
+	    dx: dx,
+	    dy: dy,
+	    run: run,
+	    jump: jump,
+	}
+    }
+    pub fn is_moving(&self) -> bool {
+	self.dx != 0 || self.dy != 0
+    }
+}
+
+lazy_static! {
+    // Movement limits, with heights in tile units. The server holds every entity to them and
+    // clients predict their own moves with the same numbers.
+    pub static ref MOVE_INTERVAL: Duration = Duration::from_millis(100);
+    pub static ref RUN_STEPS: i32 = 2;
+    pub static ref MAX_CLIMB: i32 = 1;
+    pub static ref MAX_JUMP: i32 = 2;
+}
+
+// Steps one tile at a time so a run cannot skip over water or a wall. Tiles are looked up by
+// their x and y in tiles. Only coords change, ccoords are left for whoever stores the entity.
+pub fn step_entity<'a, F>(entity: &Entity, intent: &MovementIntent, fetch_tile: F) -> Entity
+where
+    F: Fn(i32, i32) -> Option<&'a Tile>,
+{
+    let mut entity = entity.clone();
+    let (dx, dy) = (intent.dx.signum() as i32, intent.dy.signum() as i32);
+    let steps = if intent.run { *RUN_STEPS } else { 1 };
+    let climb = if intent.jump { *MAX_JUMP } else { *MAX_CLIMB };
+    for _ in 0..steps {
+	let x = (entity.coords.x / HashableF32(*TILE_SIZE as f32)).as_f32().floor() as i32;
+	let y = (entity.coords.y / HashableF32(*TILE_SIZE as f32)).as_f32().floor() as i32;
+	let from = fetch_tile(x, y);
+	let to = match fetch_tile(x + dx, y + dy) {
+	    Some(t) if t.ttype != TileType::Water => t,
+	    _ => break,
+	};
+	if let Some(from) = from {
+	    if to.coords.z - from.coords.z > climb {
+		break;
+	    }
+	}
+	entity.coords.x += HashableF32((dx * *TILE_SIZE as i32) as f32);
+	entity.coords.y += HashableF32((dy * *TILE_SIZE as i32) as f32);
+	entity.coords.z = HashableF32(to.coords.z as f32);
+    }
+    entity
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum ClientDataType {
     Chunk,
     Refresh,
+    // Every chunk within w chunks horizontally and h chunks vertically of ccoords.
+    Rect { w: i32, h: i32 },
+    // Every chunk whose centre lies within the given chunk distance of ccoords.
+    Radius(i32),
+}
+impl ClientDataType {
+    pub fn chunk_coords(&self, center: &Coords_i32) -> Vec<Coords_i32> {
+	let (w, h, radius) = match self {
+	    ClientDataType::Chunk | ClientDataType::Refresh => (0, 0, None),
+	    ClientDataType::Rect { w, h } => (*w, *h, None),
+	    ClientDataType::Radius(r) => (*r, *r, Some(*r)),
+	};
+	let mut coords = vec![];
+	for y in -h..=h {
+	    for x in -w..=w {
+		if let Some(r) = radius {
+		    if x * x + y * y > r * r {
+			continue;
+		    }
+		}
+		coords.push(Coords_i32::from((center.x + x, center.y + y, center.z)));
+	    }
+	}
+	coords
+    }
 }
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ClientData {
@@ -55,6 +161,13 @@ pub struct ClientData {
     pub action: ActionContent,
     pub data_type: ClientDataType,
     pub ccoords: Coords_i32,
+    // (chunk index, hash) of chunks the client already holds, so unchanged ones can be skipped.
+    pub known_chunks: Vec<(usize, u64)>,
+    pub intent: MovementIntent,
+    // Numbers the intent so the server can acknowledge it, 0 when there is nothing to acknowledge
+    pub sequence: u64,
+    // Token handed out on join. The server only acts on data carrying the token of this connection.
+    pub session: u64,
 }
 impl ClientData {
     pub fn new() -> ClientData {
@@ -63,6 +176,10 @@ impl ClientData {
 	    action: ActionContent::new(),
 	    data_type: ClientDataType::Chunk, 
 	    ccoords: Coords_i32::from((0,0,0)),
+	    known_chunks: vec![],
+	    intent: MovementIntent::new(),
+	    sequence: 0,
+	    session: 0,
 	}
     }
     pub fn from(entity: Entity, action: ActionContent, data_type: ClientDataType, ccoords: Coords_i32) -> ClientData {
@@ -71,6 +188,10 @@ impl ClientData {
 	    entity: entity,
 	    action: action, 
 	    data_type: data_type,
+	    known_chunks: vec![],
+	    intent: MovementIntent::new(),
+	    sequence: 0,
+	    session: 0,
 	}
     }
 }
@@ -113,3 +234,209 @@ pub struct ActionData {
     pub action: ActionType,
     pub entity: Entity,
 }
+
+lazy_static! {
+    // Bump whenever a type that crosses the wire (this file or the serialized worldgen types) changes.
+    pub static ref PROTOCOL_VERSION: u32 = 12;
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Capability {
+    Chunks,
+    BatchChunks,
+    DeltaChunks,
+    Subscriptions,
+    Chat,
+    Compression(Compression),
+}
+impl Capability {
+    pub fn supported() -> Vec<Capability> {
+	let mut capabilities = vec![
+	    Capability::Chunks,
+	    Capability::BatchChunks,
+	    Capability::DeltaChunks,
+	    Capability::Subscriptions,
+	    Capability::Chat,
+	];
+	capabilities.extend(Compression::supported().into_iter().map(Capability::Compression));
+	capabilities
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+    // Chunk and tile coordinates only line up when both sides use the same sizes.
+    pub dimensions: Dimensions,
+}
+impl Handshake {
+    pub fn new() -> Handshake {
+	Handshake {
+	    version: *PROTOCOL_VERSION,
+	    capabilities: Capability::supported(),
+	    dimensions: dimensions().clone(),
+	}
+    }
+    // The capabilities both sides understand.
+    pub fn negotiate(&self, other: &Handshake) -> Handshake {
+	Handshake {
+	    version: self.version,
+	    capabilities: self
+		.capabilities
+		.iter()
+		.filter(|c| other.capabilities.contains(c))
+		.cloned()
+		.collect(),
+	    dimensions: self.dimensions.clone(),
+	}
+    }
+    // The most preferred codec both sides negotiated, if any.
+    pub fn compression(&self) -> Compression {
+	Compression::supported()
+	    .into_iter()
+	    .find(|c| self.capabilities.contains(&Capability::Compression(*c)))
+	    .unwrap_or(Compression::None)
+    }
+}
+
+// A standing request for the chunks in area around ccoords. When follow names an entity,
+// the area is recentred on whichever chunk that entity moves into.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Subscription {
+    pub area: ClientDataType,
+    pub ccoords: Coords_i32,
+    pub follow: Option<usize>,
+}
+impl Subscription {
+    pub fn from(area: ClientDataType, ccoords: Coords_i32, follow: Option<usize>) -> Subscription {
+	Subscription {
+	    area: area,
+	    ccoords: ccoords,
+	    follow: follow,
+	}
+    }
+}
+
+// Handed out by the server on join. The entity index is allocated by the server, so a client
+// can only ever act as its own entity.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Session {
+    pub entity_index: usize,
+    pub token: u64,
+}
+impl Session {
+    pub fn from(entity_index: usize, token: u64) -> Session {
+	Session {
+	    entity_index: entity_index,
+	    token: token,
+	}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ChatChannel {
+    Global,
+    // Everyone within this many chunks of the sender
+    Proximity(i32),
+    // The sender's own faction, whatever the client asks for
+    Faction(Faction),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatMessage {
+    pub channel: ChatChannel,
+    // Entity index of the sender, None for broadcasts from the server itself.
+    // Sender and name are filled in by the server.
+    pub sender: Option<usize>,
+    pub name: String,
+    pub text: String,
+}
+impl ChatMessage {
+    pub fn from(channel: ChatChannel, text: String) -> ChatMessage {
+	ChatMessage {
+	    channel: channel,
+	    sender: None,
+	    name: "".to_string(),
+	    text: text,
+	}
+    }
+}
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let tag = match self.channel {
+	    ChatChannel::Global => "G",
+	    ChatChannel::Proximity(_) => "L",
+	    ChatChannel::Faction(_) => "F",
+	};
+	match self.sender {
+	    Some(_) => write!(f, "[{}] {}: {}", tag, self.name, self.text),
+	    None => write!(f, "[{}] * {}", tag, self.text),
+	}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ClientMessage {
+    Handshake(Handshake),
+    // The entity to spawn as. Only its position and name are kept; the server allocates
+    // the index and owns stats and inventory from then on.
+    Join(Entity),
+    Data(ClientData),
+    Subscribe(Subscription),
+    Unsubscribe,
+    Chat(ChatMessage),
+    // Takes back the entity of an earlier session after a reconnect. Answered with the same
+    // session, or InvalidSession once the server has let it go, in which case Join instead.
+    Resume(Session),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ServerError {
+    Malformed(String),
+    OutOfBounds(Coords_i32),
+    VersionMismatch { server: u32, client: u32 },
+    HandshakeRequired,
+    Unsupported(Capability),
+    JoinRequired,
+    InvalidSession,
+    DimensionMismatch { server: Dimensions, client: Dimensions },
+}
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self {
+	    ServerError::Malformed(e) => write!(f, "Server could not parse message: {}", e),
+	    ServerError::OutOfBounds(c) => write!(f, "Chunk ({}, {}) is out of bounds", c.x, c.y),
+	    ServerError::VersionMismatch { server, client } => write!(
+		f,
+		"Protocol version mismatch: server speaks {}, client speaks {}",
+		server, client
+	    ),
+	    ServerError::HandshakeRequired => write!(f, "Handshake required before sending data"),
+	    ServerError::Unsupported(c) => write!(f, "Capability {:?} was not negotiated", c),
+	    ServerError::JoinRequired => write!(f, "Join required before sending data"),
+	    ServerError::InvalidSession => write!(f, "Session token does not match this connection"),
+	    ServerError::DimensionMismatch { server, client } => write!(
+		f,
+		"World dimensions differ: server uses {:?}, client uses {:?}",
+		server, client
+	    ),
+	}
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ServerMessage {
+    Handshake(Handshake),
+    Joined(Session),
+    Chunks(Vec<Chunk>),
+    // Updates carry the server tick they were made on
+    ChunkUpdates(u64, Vec<ChunkUpdate>),
+    // Sent unprompted after a tick changed subscribed chunks; never the answer to a request.
+    Push(u64, Vec<ChunkUpdate>),
+    // The answer to ClientMessage::Chat, as the server delivered it to the channel.
+    ChatSent(ChatMessage),
+    // Sent unprompted for chat from others and server broadcasts; never the answer to a request.
+    Chat(ChatMessage),
+    Error(ServerError),
+}
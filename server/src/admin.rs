@@ -0,0 +1,500 @@
+use crate::{ChatEvent, Shared};
+use dimensioner_server::simulation::{WorldCommand, FIRST_PLACED_INDEX};
+use dimensioner_server::snapshot::WorldSnapshot;
+use dimensioner_server::util::{ChatChannel, ChatMessage};
+use dimensioner_server::worldgen::*;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::task;
+
+// Routes, all JSON:
+//   GET    /worlds                         worlds with their time and entity counts
+//   GET    /worlds/{w}/chunks              chunk summaries
+//   GET    /worlds/{w}/chunks/{x}/{y}      one chunk, as Chunk::as_string gives it
+//   GET    /players                        connected players
+//   POST   /worlds/{w}/entities            {"etype": "Cannon", "x": .., "y": .., "z": ..}
+//   DELETE /worlds/{w}/entities/{index}
+//   POST   /worlds/{w}/entities/{index}/transfer   {"world": 1, "x": .., "y": ..}, into another world
+//   POST   /portals                        {"from": {"world": 0, "x": .., "y": ..}, "to": {..}}
+//   GET    /tick-rate
+//   PUT    /tick-rate                      {"tick_rate": 60}
+//   POST   /save                           writes every world to the save directory
+//   POST   /broadcast                      {"text": ".."}, told to every player in chat
+// Changes to the world are queued like any client's and show up from the next tick.
+
+type Reply = Result<Response<Body>, (StatusCode, String)>;
+
+static NEXT_PLACED: AtomicUsize = AtomicUsize::new(0);
+
+// Entities placed here are numbered apart from players and what they build.
+fn next_placed_index() -> usize {
+    *FIRST_PLACED_INDEX + NEXT_PLACED.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Serialize)]
+struct WorldInfo {
+    index: usize,
+    tick: u64,
+    time: u64,
+    chunks: usize,
+    entities: usize,
+}
+
+#[derive(Serialize)]
+struct ChunkInfo {
+    index: usize,
+    coords: Coords_i32,
+    hash: u64,
+    entities: usize,
+    settlement: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PlayerInfo {
+    index: usize,
+    name: String,
+    world: usize,
+    coords: Option<Coords_f32>,
+    ccoords: Option<Coords_i32>,
+}
+
+#[derive(Deserialize)]
+struct SpawnRequest {
+    etype: EntityType,
+    // World coordinates, the same units as Entity.coords
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    z: f32,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TransferRequest {
+    world: usize,
+    // Where it lands, the same place as before if left out
+    x: Option<f32>,
+    y: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct Place {
+    world: usize,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    z: f32,
+}
+
+// A pair of portals, each leading to where the other stands
+#[derive(Deserialize)]
+struct PortalRequest {
+    from: Place,
+    to: Place,
+}
+
+#[derive(Serialize)]
+struct Portals {
+    from: Entity,
+    to: Entity,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TickRate {
+    tick_rate: u32,
+}
+
+#[derive(Deserialize)]
+struct Broadcast {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct Saved {
+    tick: u64,
+    files: Vec<String>,
+}
+
+pub struct Admin {
+    shared: Shared,
+    save_dir: String,
+}
+impl Admin {
+    pub fn from(shared: Shared, save_dir: String) -> Admin {
+        Admin {
+            shared: shared,
+            save_dir: save_dir,
+        }
+    }
+    async fn route(&self, req: Request<Body>) -> Reply {
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (method, segments.as_slice()) {
+            (Method::GET, ["worlds"]) => self.worlds(),
+            (Method::GET, ["worlds", w, "chunks"]) => self.chunks(parse(w)?),
+            (Method::GET, ["worlds", w, "chunks", x, y]) => {
+                self.chunk(parse(w)?, parse(x)?, parse(y)?)
+            }
+            (Method::GET, ["players"]) => self.players(),
+            (Method::POST, ["worlds", w, "entities"]) => {
+                let world = parse(w)?;
+                self.spawn(world, read_json(req).await?)
+            }
+            (Method::DELETE, ["worlds", w, "entities", index]) => {
+                self.remove(parse(w)?, parse(index)?)
+            }
+            (Method::POST, ["worlds", w, "entities", index, "transfer"]) => {
+                let (world, index) = (parse(w)?, parse(index)?);
+                self.transfer(world, index, read_json(req).await?)
+            }
+            (Method::POST, ["portals"]) => self.portals(read_json(req).await?),
+            (Method::GET, ["tick-rate"]) => json(
+                StatusCode::OK,
+                &TickRate {
+                    tick_rate: self.shared.tick_rate.load(Ordering::Relaxed),
+                },
+            ),
+            (Method::PUT, ["tick-rate"]) => self.set_tick_rate(read_json(req).await?),
+            (Method::POST, ["save"]) => self.save().await,
+            (Method::POST, ["broadcast"]) => self.broadcast(read_json(req).await?),
+            _ => Err((StatusCode::NOT_FOUND, format!("No route for {}", path))),
+        }
+    }
+    fn world(&self, index: usize) -> Result<WorldSnapshot, (StatusCode, String)> {
+        self.shared
+            .snapshots
+            .load()
+            .worlds
+            .get(index)
+            .cloned()
+            .ok_or((StatusCode::NOT_FOUND, format!("No world {}", index)))
+    }
+    fn worlds(&self) -> Reply {
+        let snapshot = self.shared.snapshots.load();
+        let worlds: Vec<WorldInfo> = snapshot
+            .worlds
+            .iter()
+            .enumerate()
+            .map(|(i, w)| WorldInfo {
+                index: i,
+                tick: snapshot.tick,
+                time: w.time,
+                chunks: w.chunks.len(),
+                entities: w.chunks.iter().map(|c| c.entities.len()).sum(),
+            })
+            .collect();
+        json(StatusCode::OK, &worlds)
+    }
+    fn chunks(&self, world: usize) -> Reply {
+        let chunks: Vec<ChunkInfo> = self
+            .world(world)?
+            .chunks
+            .iter()
+            .map(|c| ChunkInfo {
+                index: c.index,
+                coords: c.coords.clone(),
+                hash: c.hash,
+                entities: c.entities.len(),
+                settlement: c.settlement.as_ref().map(|s| s.name.clone()),
+            })
+            .collect();
+        json(StatusCode::OK, &chunks)
+    }
+    fn chunk(&self, world: usize, x: i32, y: i32) -> Reply {
+        let world = self.world(world)?;
+        let index = chunk_index(&Coords_i32::from((x, y, 0))).ok_or((
+            StatusCode::NOT_FOUND,
+            format!("Chunk {},{} is outside the world", x, y),
+        ))?;
+        let body = world.fetch_chunk(index).as_string().map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to serialize chunk: {}", e),
+            )
+        })?;
+        Ok(response(StatusCode::OK, body))
+    }
+    fn players(&self) -> Reply {
+        let mut indices: Vec<usize> = self
+            .shared
+            .inputs
+            .sessions
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        indices.sort();
+        let snapshot = self.shared.snapshots.load();
+        let players: Vec<PlayerInfo> = indices
+            .into_iter()
+            .map(|index| {
+                let found = snapshot.locate(index).and_then(|world| {
+                    snapshot.worlds[world]
+                        .fetch_entity(index)
+                        .map(|e| (world, e))
+                });
+                match found {
+                    Some((world, e)) => PlayerInfo {
+                        index: index,
+                        name: e.name.clone(),
+                        world: world,
+                        coords: Some(e.coords.clone()),
+                        ccoords: Some(e.ccoords.clone()),
+                    },
+                    // Joined, but not spawned yet
+                    None => PlayerInfo {
+                        index: index,
+                        name: "".to_string(),
+                        world: 0,
+                        coords: None,
+                        ccoords: None,
+                    },
+                }
+            })
+            .collect();
+        json(StatusCode::OK, &players)
+    }
+    fn spawn(&self, world: usize, request: SpawnRequest) -> Reply {
+        self.world(world)?;
+        let coords = Coords_f32::from((request.x, request.y, request.z));
+        let mut entity = Entity::from(
+            next_placed_index(),
+            coords,
+            (0.0, 0.0, 0.0),
+            request.etype,
+            Stats::gen(),
+            Alignment::from(Faction::Empty),
+            gen_human_name(Faction::Empty, &Gender::Other),
+            Gender::Other,
+            world,
+        );
+        if let Some(name) = request.name {
+            entity.name = name;
+        }
+        if chunk_index(&entity.ccoords).is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{},{} is outside the world", request.x, request.y),
+            ));
+        }
+        self.command(WorldCommand::Spawn(world, entity.clone()))?;
+        json(StatusCode::ACCEPTED, &entity)
+    }
+    fn remove(&self, world: usize, index: usize) -> Reply {
+        let entity = self.world(world)?.fetch_entity(index).cloned().ok_or((
+            StatusCode::NOT_FOUND,
+            format!("No entity {} in world {}", index, world),
+        ))?;
+        self.command(WorldCommand::Despawn(index))?;
+        json(StatusCode::ACCEPTED, &entity)
+    }
+    fn transfer(&self, world: usize, index: usize, request: TransferRequest) -> Reply {
+        let entity = self.world(world)?.fetch_entity(index).cloned().ok_or((
+            StatusCode::NOT_FOUND,
+            format!("No entity {} in world {}", index, world),
+        ))?;
+        self.world(request.world)?;
+        let x = request.x.unwrap_or(entity.coords.x.as_f32());
+        let y = request.y.unwrap_or(entity.coords.y.as_f32());
+        let size = (*TILE_SIZE * *CHUNK_SIZE) as f32;
+        let ccoords = Coords_i32::from(((x / size).floor() as i32, (y / size).floor() as i32, 0));
+        if chunk_index(&ccoords).is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{},{} is outside the world", x, y),
+            ));
+        }
+        let coords = Coords_f32::from((x, y, entity.coords.z.as_f32()));
+        let moved = Entity {
+            coords: coords.clone(),
+            ccoords: ccoords,
+            current_world: request.world,
+            ..entity
+        };
+        self.command(WorldCommand::Transfer(index, request.world, coords))?;
+        json(StatusCode::ACCEPTED, &moved)
+    }
+    fn portals(&self, request: PortalRequest) -> Reply {
+        let mut from = self.portal(&request.from)?;
+        let mut to = self.portal(&request.to)?;
+        from.linked_entity_id = to.index as u64;
+        to.linked_entity_id = from.index as u64;
+        self.command(WorldCommand::Spawn(request.from.world, from.clone()))?;
+        self.command(WorldCommand::Spawn(request.to.world, to.clone()))?;
+        json(StatusCode::ACCEPTED, &Portals { from: from, to: to })
+    }
+    // A portal on the tile under the given place
+    fn portal(&self, place: &Place) -> Result<Entity, (StatusCode, String)> {
+        self.world(place.world)?;
+        let tile = *TILE_SIZE as f32;
+        let coords = Coords_f32::from((
+            (place.x / tile).floor() * tile,
+            (place.y / tile).floor() * tile,
+            place.z,
+        ));
+        let entity = Entity::from(
+            next_placed_index(),
+            coords,
+            (0.0, 0.0, 0.0),
+            EntityType::Portal,
+            Stats::gen(),
+            Alignment::from(Faction::Empty),
+            format!("Portal {}", place.world),
+            Gender::Other,
+            place.world,
+        );
+        if chunk_index(&entity.ccoords).is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("{},{} is outside the world", place.x, place.y),
+            ));
+        }
+        Ok(entity)
+    }
+    fn set_tick_rate(&self, request: TickRate) -> Reply {
+        if request.tick_rate == 0 || request.tick_rate > 1000 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Tick rate must be 1-1000, got {}", request.tick_rate),
+            ));
+        }
+        self.shared
+            .tick_rate
+            .store(request.tick_rate, Ordering::Relaxed);
+        json(StatusCode::OK, &request)
+    }
+    // Writes the latest snapshot, so the simulation keeps ticking while the files are written.
+    async fn save(&self) -> Reply {
+        let snapshot = self.shared.snapshots.load_full();
+        let save_dir = self.save_dir.clone();
+        let saved = task::spawn_blocking(move || -> Result<Saved, String> {
+            fs::create_dir_all(&save_dir)
+                .map_err(|e| format!("Cannot use save directory {}: {}", save_dir, e))?;
+            let mut files = vec![];
+            for (i, w) in snapshot.worlds.iter().enumerate() {
+                let world = World::from(
+                    w.chunks.iter().map(|c| (**c).clone()).collect(),
+                    (*w.settlements).clone(),
+                    w.time,
+                );
+                let bytes = bincode::serialize(&world)
+                    .map_err(|e| format!("Failed to serialize world {}: {}", i, e))?;
+                let path = Path::new(&save_dir).join(format!("world-{}.bin", i));
+                let partial = path.with_extension("bin.partial");
+                fs::write(&partial, bytes)
+                    .and_then(|_| fs::rename(&partial, &path))
+                    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+                files.push(path.display().to_string());
+            }
+            Ok(Saved {
+                tick: snapshot.tick,
+                files: files,
+            })
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|saved| saved)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        json(StatusCode::OK, &saved)
+    }
+    fn broadcast(&self, request: Broadcast) -> Reply {
+        let text = request.text.trim().to_string();
+        if text.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "Empty broadcast".to_string()));
+        }
+        let message = ChatMessage::from(ChatChannel::Global, text);
+        // Nobody listening is not an error, there is just nobody to tell
+        let _ = self.shared.chat.send(ChatEvent {
+            message: message.clone(),
+            world: 0,
+            ccoords: Coords_i32::new(),
+        });
+        json(StatusCode::OK, &message)
+    }
+    fn command(&self, command: WorldCommand) -> Result<(), (StatusCode, String)> {
+        self.shared.inputs.tx_w.send(command).map_err(|_| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The simulation is not running".to_string(),
+            )
+        })
+    }
+}
+
+pub async fn serve(addr: SocketAddr, admin: Admin) {
+    let admin = Arc::new(admin);
+    let make_service = make_service_fn(move |_| {
+        let admin = Arc::clone(&admin);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let admin = Arc::clone(&admin);
+                async move {
+                    let reply = match admin.route(req).await {
+                        Ok(reply) => reply,
+                        Err((status, e)) => {
+                            response(status, serde_json::json!({ "error": e }).to_string())
+                        }
+                    };
+                    Ok::<_, Infallible>(reply)
+                }
+            }))
+        }
+    });
+    match Server::try_bind(&addr) {
+        Ok(server) => {
+            if let Err(e) = server.serve(make_service).await {
+                eprintln!("Admin API stopped: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Failed to start admin API on {}: {}", addr, e),
+    }
+}
+
+fn parse<T: std::str::FromStr>(segment: &str) -> Result<T, (StatusCode, String)> {
+    segment.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid path segment {}", segment),
+        )
+    })
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<T, (StatusCode, String)> {
+    let body = hyper::body::to_bytes(req.into_body()).await.map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Failed to read body: {}", e),
+        )
+    })?;
+    serde_json::from_slice(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid JSON body: {}", e)))
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Reply {
+    let body = serde_json::to_string(value).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to serialize response: {}", e),
+        )
+    })?;
+    Ok(response(status, body))
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
@@ -0,0 +1,303 @@
+use crate::worldgen::Dimensions;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+lazy_static! {
+    pub static ref USAGE: String = "Usage: dimensioner_server [--config server.toml] [--listen addr] \
+[--websocket-listen addr] [--admin-listen addr] [--seed n] [--generator worldgen|globegen] [--world-size n] \
+[--chunk-size n] [--tile-size n] [--noise-scale x] [--tick-rate n] [--map path] [--save-dir path] \
+[--extra-world worldgen:seed|globegen:path] [--record path] [--replay path] [--disconnect-grace secs] \
+[--disconnect-action sleep|despawn]"
+        .to_string();
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Generator {
+    Worldgen,
+    Globegen,
+}
+
+// What happens to the entity of a player whose connection did not come back in time
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisconnectAction {
+    // Taken out of the world and kept in the save directory until the session is resumed
+    Sleep,
+    Despawn,
+}
+
+// A world hosted next to the main one
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WorldConfig {
+    pub generator: Generator,
+    #[serde(default)]
+    pub seed: u32,
+    // Heightmap for globegen, the main map if left out
+    pub map: Option<String>,
+}
+impl WorldConfig {
+    // worldgen:seed or globegen:path, the part after the colon is optional
+    pub fn from(spec: &str) -> Result<WorldConfig, String> {
+        let (generator, rest) = match spec.split_once(':') {
+            Some((generator, rest)) => (generator, Some(rest)),
+            None => (spec, None),
+        };
+        match generator {
+            "worldgen" => Ok(WorldConfig {
+                generator: Generator::Worldgen,
+                seed: match rest {
+                    Some(seed) => parse("--extra-world", seed)?,
+                    None => 0,
+                },
+                map: None,
+            }),
+            "globegen" => Ok(WorldConfig {
+                generator: Generator::Globegen,
+                seed: 0,
+                map: rest.map(|m| m.to_string()),
+            }),
+            other => Err(format!("Unknown generator {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub websocket_listen: String,
+    // The admin API has no authentication, keep it on a loopback address
+    pub admin_listen: String,
+    pub seed: u32,
+    pub generator: Generator,
+    pub world_size: u32,
+    pub chunk_size: u32,
+    pub tile_size: u32,
+    pub noise_scale: f64,
+    // Ticks per second
+    pub tick_rate: u32,
+    // Seconds a disconnected player's entity stays in the world, waiting for the session to resume
+    pub disconnect_grace: u64,
+    pub disconnect_action: DisconnectAction,
+    // Heightmap used by globegen
+    pub map: String,
+    pub save_dir: String,
+    // Hosted after the main world, as worlds 1, 2 and so on
+    pub extra_worlds: Vec<WorldConfig>,
+    // Every tick's input is written here when set
+    pub record: Option<String>,
+    // Replays a recording without opening any sockets, then exits
+    pub replay: Option<String>,
+}
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+impl Config {
+    pub fn new() -> Config {
+        let dimensions = Dimensions::new();
+        Config {
+            listen: "127.0.0.1:3000".to_string(),
+            websocket_listen: "127.0.0.1:3001".to_string(),
+            admin_listen: "127.0.0.1:3002".to_string(),
+            seed: 0,
+            generator: Generator::Worldgen,
+            world_size: dimensions.world_size,
+            chunk_size: dimensions.chunk_size,
+            tile_size: dimensions.tile_size,
+            noise_scale: dimensions.noise_scale,
+            tick_rate: 120,
+            disconnect_grace: 30,
+            disconnect_action: DisconnectAction::Sleep,
+            map: "data/map/globe.gif".to_string(),
+            save_dir: "save".to_string(),
+            extra_worlds: vec![],
+            record: None,
+            replay: None,
+        }
+    }
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let toml_str =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        toml::from_str(&toml_str).map_err(|e| format!("Failed to parse {}: {}", path, e))
+    }
+    // Reads --config first, then lets the other flags override what the file says.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = match flag_value(args, "--config")? {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new("server.toml").exists() => Config::from_file("server.toml")?,
+            None => Config::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or(format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                "--config" => {
+                    value()?;
+                }
+                "--listen" => config.listen = value()?,
+                "--websocket-listen" => config.websocket_listen = value()?,
+                "--admin-listen" => config.admin_listen = value()?,
+                "--seed" => config.seed = parse(arg, &value()?)?,
+                "--generator" => {
+                    config.generator = match value()?.as_str() {
+                        "worldgen" => Generator::Worldgen,
+                        "globegen" => Generator::Globegen,
+                        other => return Err(format!("Unknown generator {}", other)),
+                    }
+                }
+                "--world-size" => config.world_size = parse(arg, &value()?)?,
+                "--chunk-size" => config.chunk_size = parse(arg, &value()?)?,
+                "--tile-size" => config.tile_size = parse(arg, &value()?)?,
+                "--noise-scale" => config.noise_scale = parse(arg, &value()?)?,
+                "--tick-rate" => config.tick_rate = parse(arg, &value()?)?,
+                "--disconnect-grace" => config.disconnect_grace = parse(arg, &value()?)?,
+                "--disconnect-action" => {
+                    config.disconnect_action = match value()?.as_str() {
+                        "sleep" => DisconnectAction::Sleep,
+                        "despawn" => DisconnectAction::Despawn,
+                        other => return Err(format!("Unknown disconnect action {}", other)),
+                    }
+                }
+                "--map" => config.map = value()?,
+                "--save-dir" => config.save_dir = value()?,
+                "--extra-world" => config.extra_worlds.push(WorldConfig::from(&value()?)?),
+                "--record" => config.record = Some(value()?),
+                "--replay" => config.replay = Some(value()?),
+                other => return Err(format!("Unknown argument {}\n{}", other, *USAGE)),
+            }
+        }
+        Ok(config)
+    }
+    pub fn validate(&self) -> Result<(), String> {
+        self.listen
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid listen address {}: {}", self.listen, e))?;
+        self.websocket_listen.parse::<SocketAddr>().map_err(|e| {
+            format!("Invalid WebSocket listen address {}: {}", self.websocket_listen, e)
+        })?;
+        self.admin_listen.parse::<SocketAddr>().map_err(|e| {
+            format!("Invalid admin listen address {}: {}", self.admin_listen, e)
+        })?;
+        let addresses = [&self.listen, &self.websocket_listen, &self.admin_listen];
+        for (i, a) in addresses.iter().enumerate() {
+            if addresses[..i].contains(a) {
+                return Err(format!("Two listeners cannot both use {}", a));
+            }
+        }
+        if self.world_size == 0 || self.chunk_size == 0 || self.tile_size == 0 {
+            return Err("World, chunk and tile sizes must be positive".to_string());
+        }
+        if !(self.noise_scale > 0.0) {
+            return Err(format!("Noise scale must be positive, got {}", self.noise_scale));
+        }
+        if self.tick_rate == 0 || self.tick_rate > 1000 {
+            return Err(format!("Tick rate must be 1-1000, got {}", self.tick_rate));
+        }
+        for world in self.worlds() {
+            if let (Generator::Globegen, Some(map)) = (&world.generator, &world.map) {
+                if !Path::new(map).is_file() {
+                    return Err(format!("Map image {} does not exist", map));
+                }
+            }
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err("Cannot record while replaying".to_string());
+        }
+        // The directory itself is only made once something is saved into it
+        if Path::new(&self.save_dir).exists() && !Path::new(&self.save_dir).is_dir() {
+            return Err(format!("Save directory {} is not a directory", self.save_dir));
+        }
+        Ok(())
+    }
+    // Every hosted world in index order, the main one first
+    pub fn worlds(&self) -> Vec<WorldConfig> {
+        let main = WorldConfig {
+            generator: self.generator.clone(),
+            seed: self.seed,
+            map: None,
+        };
+        std::iter::once(main)
+            .chain(self.extra_worlds.iter().cloned())
+            .map(|w| WorldConfig {
+                map: w.map.or(Some(self.map.clone())),
+                ..w
+            })
+            .collect()
+    }
+    pub fn dimensions(&self) -> Dimensions {
+        Dimensions {
+            world_size: self.world_size,
+            chunk_size: self.chunk_size,
+            tile_size: self.tile_size,
+            noise_scale: self.noise_scale,
+        }
+    }
+}
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Listen address: {}", self.listen)?;
+        writeln!(f, "WebSocket:      {}", self.websocket_listen)?;
+        writeln!(f, "Admin API:      {}", self.admin_listen)?;
+        writeln!(f, "Generator:      {:?} (seed {})", self.generator, self.seed)?;
+        writeln!(
+            f,
+            "World:          {0}x{0} chunks of {1}x{1} tiles, tile size {2}, noise scale {3}",
+            self.world_size, self.chunk_size, self.tile_size, self.noise_scale
+        )?;
+        writeln!(f, "Tick rate:      {} per second", self.tick_rate)?;
+        writeln!(
+            f,
+            "Disconnects:    {:?} after {}s",
+            self.disconnect_action, self.disconnect_grace
+        )?;
+        writeln!(f, "Map image:      {}", self.map)?;
+        for (i, w) in self.extra_worlds.iter().enumerate() {
+            match w.generator {
+                Generator::Worldgen => writeln!(f, "World {}:        Worldgen (seed {})", i + 1, w.seed)?,
+                Generator::Globegen => writeln!(
+                    f,
+                    "World {}:        Globegen ({})",
+                    i + 1,
+                    w.map.as_ref().unwrap_or(&self.map)
+                )?,
+            }
+        }
+        if let Some(path) = &self.record {
+            writeln!(f, "Recording to:   {}", path)?;
+        }
+        write!(f, "Save directory: {}", self.save_dir)
+    }
+}
+
+fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => args
+            .get(i + 1)
+            .cloned()
+            .map(Some)
+            .ok_or(format!("Missing value for {}", flag)),
+        None => Ok(None),
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {} for {}: {}", value, flag, e))
+}
@@ -1,4 +1,11 @@
+pub mod config;
+pub mod frame;
 pub mod lang;
+pub mod local;
 pub mod math;
+pub mod recording;
+pub mod serving;
+pub mod simulation;
+pub mod snapshot;
 pub mod util;
 pub mod worldgen;
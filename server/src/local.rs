@@ -0,0 +1,184 @@
+use crate::config::Config;
+use crate::serving::{sign_chat, Inputs, View, FIRST_PLAYER_INDEX};
+use crate::simulation::{Simulation, WorldCommand};
+use crate::snapshot::Snapshot;
+use crate::util::{ClientMessage, Handshake, ServerError, ServerMessage, Session};
+use crate::worldgen::set_dimensions;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// The worlds and tick loop of the server, run inside a client for playing without one. Players
+// reach it through a LocalPeer each, with the same messages a server gets over the network, so a
+// Connection works the same either way. Cloning hands out another handle to the same worlds,
+// which keep ticking until the last handle and peer are dropped.
+#[derive(Clone)]
+pub struct LocalServer {
+    inputs: Inputs,
+    // Replaced by the next one after every tick
+    snapshots: Arc<Mutex<Arc<Snapshot>>>,
+    ticks: watch::Receiver<u64>,
+    next_player: Arc<AtomicUsize>,
+}
+impl LocalServer {
+    // Generates the worlds before returning, which takes a while for a large one. Only the
+    // seeds, generators, dimensions and tick rate of the config matter here.
+    pub fn start(config: &Config) -> Result<LocalServer, String> {
+        set_dimensions(config.dimensions())
+            .map_err(|d| format!("World dimensions already fixed to {:?}", d))?;
+        let (inputs, queue) = Inputs::new();
+        let (tx_tick, rx_tick) = watch::channel(0u64);
+        let mut simulation = Simulation::from(&config.worlds(), config.seed as u64);
+        let snapshots = Arc::new(Mutex::new(Arc::new(Snapshot::from(&simulation.worlds))));
+        let snapshots_c = Arc::clone(&snapshots);
+        let tick_rate = config.tick_rate.max(1);
+        thread::spawn(move || {
+            let started = Instant::now();
+            loop {
+                let previous = Arc::clone(&snapshots_c.lock().unwrap());
+                // Nobody is left to play once every handle is gone
+                let (_, snapshot) =
+                    match queue.tick(&mut simulation, vec![], &previous, started.elapsed()) {
+                        Some(tick) => tick,
+                        None => return,
+                    };
+                *snapshots_c.lock().unwrap() = Arc::new(snapshot);
+                let _ = tx_tick.send(simulation.tick);
+                thread::sleep(Duration::from_secs_f64(1.0 / tick_rate as f64));
+            }
+        });
+        Ok(LocalServer {
+            inputs: inputs,
+            snapshots: snapshots,
+            ticks: rx_tick,
+            next_player: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+    fn snapshot(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.snapshots.lock().unwrap())
+    }
+}
+
+// One player's end of a LocalServer, in place of a connection. Every message is answered as it
+// is sent, so recv only ever waits for pushes to a subscription. Dropping it takes the player's
+// entity out of the world.
+pub struct LocalPeer {
+    server: LocalServer,
+    ticks: watch::Receiver<u64>,
+    view: View,
+    session: Option<Session>,
+    replies: VecDeque<ServerMessage>,
+}
+impl LocalPeer {
+    pub fn from(server: LocalServer) -> LocalPeer {
+        LocalPeer {
+            ticks: server.ticks.clone(),
+            server: server,
+            view: View::new(),
+            session: None,
+            replies: VecDeque::new(),
+        }
+    }
+    pub fn send(&mut self, message: ClientMessage) {
+        let reply = self.handle(message);
+        self.replies.push_back(reply);
+    }
+    // Whatever changed in the subscribed area since a tick went by comes before the replies to
+    // what was sent, as it would over the network. None once the simulation has stopped.
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        let mut ticked = self.ticks.has_changed().ok()?;
+        loop {
+            if ticked {
+                self.ticks.borrow_and_update();
+                if let Some(push) = self.push() {
+                    return Some(push);
+                }
+            }
+            if let Some(reply) = self.replies.pop_front() {
+                return Some(reply);
+            }
+            self.ticks.changed().await.ok()?;
+            ticked = true;
+        }
+    }
+    fn push(&mut self) -> Option<ServerMessage> {
+        if !self.view.subscribed() {
+            return None;
+        }
+        let snapshot = self.server.snapshot();
+        self.view.push(&snapshot, self.session.as_ref())
+    }
+    // There is no link to negotiate, so nothing waits on a handshake and every capability is on.
+    fn handle(&mut self, message: ClientMessage) -> ServerMessage {
+        match message {
+            ClientMessage::Handshake(h) => ServerMessage::Handshake(Handshake::new().negotiate(&h)),
+            ClientMessage::Join(_) if self.session.is_some() => {
+                ServerMessage::Joined(self.session.clone().unwrap())
+            }
+            ClientMessage::Join(requested) => {
+                let index =
+                    *FIRST_PLAYER_INDEX + self.server.next_player.fetch_add(1, Ordering::Relaxed);
+                let snapshot = self.server.snapshot();
+                let joined = self.server.inputs.join(index, requested, &snapshot);
+                self.session = Some(joined.clone());
+                ServerMessage::Joined(joined)
+            }
+            // A local peer never loses its link, so there is never another session to go back to
+            ClientMessage::Resume(session) if self.session.as_ref() == Some(&session) => {
+                ServerMessage::Joined(session)
+            }
+            ClientMessage::Resume(_) => ServerMessage::Error(ServerError::InvalidSession),
+            ClientMessage::Data(_) | ClientMessage::Chat(_) if self.session.is_none() => {
+                ServerMessage::Error(ServerError::JoinRequired)
+            }
+            ClientMessage::Data(client_data)
+                if Some(client_data.session) != self.session.as_ref().map(|s| s.token) =>
+            {
+                ServerMessage::Error(ServerError::InvalidSession)
+            }
+            ClientMessage::Data(client_data) => self.view.data(
+                &self.server.snapshot(),
+                &self.server.inputs,
+                self.session.as_ref().unwrap(),
+                client_data,
+                true,
+            ),
+            ClientMessage::Subscribe(s) => {
+                let reply = self
+                    .view
+                    .subscribe(&self.server.snapshot(), self.session.as_ref(), s);
+                self.ticks.borrow_and_update();
+                reply
+            }
+            ClientMessage::Unsubscribe => self.view.unsubscribe(&self.server.snapshot()),
+            // Nobody else is around to hear it, so it only comes back as sent
+            ClientMessage::Chat(message) => {
+                let snapshot = self.server.snapshot();
+                match sign_chat(&snapshot, self.session.as_ref().unwrap(), message) {
+                    Ok((message, _, _)) => ServerMessage::ChatSent(message),
+                    Err(e) => ServerMessage::Error(e),
+                }
+            }
+        }
+    }
+}
+impl Drop for LocalPeer {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.server
+                .inputs
+                .sessions
+                .lock()
+                .unwrap()
+                .remove(&session.entity_index);
+            let _ = self
+                .server
+                .inputs
+                .tx_w
+                .send(WorldCommand::Despawn(session.entity_index));
+        }
+    }
+}
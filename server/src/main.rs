@@ -1,329 +1,685 @@
+use arc_swap::ArcSwap;
 use bincode;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use dimensioner_server::config::{Config, DisconnectAction};
+use dimensioner_server::frame::{deserialize_frame, FrameReader, FrameWriter, MAX_FRAME_SIZE};
+use dimensioner_server::recording::{replay, Recorder};
+use dimensioner_server::serving::{sign_chat, Inputs, View, FIRST_PLAYER_INDEX};
+use dimensioner_server::simulation::{Simulation, WorldCommand};
+use dimensioner_server::snapshot::Snapshot;
 use dimensioner_server::util::RenderMsg;
-use dimensioner_server::util::{ActionData, ActionType, ClientData, ClientDataType};
+use dimensioner_server::util::{
+    Capability, ChatChannel, ChatMessage, ClientMessage, Handshake, ServerError, ServerMessage,
+    Session, PROTOCOL_VERSION,
+};
 use dimensioner_server::worldgen::*;
+use futures_util::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
-use std::io::{self, Read, Write};
+use sleepers::{Sleeper, Sleepers};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
 use tokio::task;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+
+mod admin;
+mod sleepers;
 
 lazy_static! {
     pub static ref PARTITION_SIZE: usize = (*WORLD_SIZE as usize * *WORLD_SIZE as usize) / 16;
+    // Chat a slow connection has not picked up by then is skipped over
+    pub static ref CHAT_BUFFER: usize = 256;
+    // How often players hear the news of the chunk they are in
+    pub static ref NEWS_INTERVAL: Duration = Duration::from_secs(60);
+}
+
+static NEXT_PLAYER: AtomicUsize = AtomicUsize::new(0);
+
+// Sessions whose connection went away, with when, by entity index
+type Detached = Arc<Mutex<HashMap<usize, (Session, Instant)>>>;
+
+// A chat message on its way out, with what it takes to pick its recipients.
+#[derive(Clone)]
+struct ChatEvent {
+    message: ChatMessage,
+    world: usize,
+    // Where it was said, for proximity channels
+    ccoords: Coords_i32,
+}
+
+// Written from the blocking pool, so the disk holds up neither the tick nor a resume.
+fn save_sleepers(sleepers: &Mutex<Sleepers>) {
+    let pending = sleepers.lock().unwrap().pending();
+    task::spawn_blocking(move || {
+        if let Err(e) = pending.write() {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+fn next_entity_index() -> usize {
+    *FIRST_PLAYER_INDEX + NEXT_PLAYER.fetch_add(1, Ordering::Relaxed)
 }
 
 #[tokio::main]
 async fn main() {
-    // Create a broadcast channel
-    let (tx, _rx) = unbounded();
-    let (tx_c, mut rx_c): (Sender<ClientData>, Receiver<ClientData>) = unbounded();
-    let (tx_c_a, mut rx_c_a): (Sender<ClientData>, Receiver<ClientData>) = unbounded();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::from_args(&args).and_then(|c| c.validate().map(|_| c)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // A replay brings its own dimensions and worlds, and needs nothing else
+    if let Some(path) = &config.replay {
+        match replay(path) {
+            Ok(report) if report.mismatches.is_empty() => println!("{}", report),
+            Ok(report) => {
+                println!("{}", report);
+                std::process::exit(2);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // Nothing may read the world constants before this
+    if let Err(d) = set_dimensions(config.dimensions()) {
+        eprintln!("World dimensions already fixed to {:?}", d);
+        std::process::exit(1);
+    }
+    println!("{}", config);
+
+    let (inputs, queue) = Inputs::new();
+    // Bumped after every tick so subscribed connections know to push changes
+    let (tx_tick, rx_tick) = watch::channel(0u64);
+    let (tx_chat, _) = broadcast::channel::<ChatEvent>(*CHAT_BUFFER);
+    let tx_chat_c = tx_chat.clone();
 
-    let mut worlds = Arc::new(Mutex::new(vec![]));
-    let mut rng = rand::thread_rng();
-    for _ in 0..1 {
-        let seed = rng.gen_range(0..1000);
-        //worlds.push(worldgen(seed));
-        worlds.lock().unwrap().push(worldgen(0));
+    let detached: Detached = Arc::new(Mutex::new(HashMap::new()));
+    let sleepers = match Sleepers::load(&config.save_dir) {
+        Ok(sleepers) => sleepers,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // New players must not be handed the index of a sleeping one
+    if let Some(index) = sleepers.max_index() {
+        if index >= *FIRST_PLAYER_INDEX {
+            NEXT_PLAYER.store(index - *FIRST_PLAYER_INDEX + 1, Ordering::Relaxed);
+        }
     }
+    if sleepers.len() > 0 {
+        println!("{} sleeping players", sleepers.len());
+    }
+    let sleepers = Arc::new(Mutex::new(sleepers));
+    let mut simulation = Simulation::from(&config.worlds(), config.seed as u64);
+    let snapshots = Arc::new(ArcSwap::from_pointee(Snapshot::from(&simulation.worlds)));
+    let mut recorder = match &config.record {
+        Some(path) => match Recorder::create(path, &config.worlds(), &simulation) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // Read every tick, so the admin API can change it while running
+    let tick_rate = Arc::new(AtomicU32::new(config.tick_rate));
+    let tick_rate_c = Arc::clone(&tick_rate);
 
-    let mut step_increment = 1;
+    let snapshots_c = Arc::clone(&snapshots);
+    let detached_c = Arc::clone(&detached);
+    let sleepers_c = Arc::clone(&sleepers);
+    let grace = Duration::from_secs(config.disconnect_grace);
+    let disconnect_action = config.disconnect_action.clone();
 
     // Spawn a worker thread to send "world data" every few seconds
     task::spawn(async move {
-        let mut rng = StdRng::from_entropy(); // Separate RNG instance for this task
+        let started = Instant::now();
+        let mut last_news = Instant::now();
+        // Last news told about each chunk, so the same story is not repeated
+        let mut reported_news: HashMap<(usize, usize), Vec<String>> = HashMap::new();
         loop {
-            if let Ok(o) = rx_c_a.try_recv() {
-                match o.entity.current_action {
-                    ActionType::Empty => {}
-                    ActionType::Refresh => {}
-                    ActionType::ConstructCannon => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        let mut entity = Entity::from(
-                            rng.gen_range(0..1000) as usize,
-                            coords,
-                            (0.0, 0.0, 0.0),
-                            EntityType::Cannon,
-                            Stats::gen(),
-                            Alignment::from(Faction::Marine),
-                            gen_human_name(Faction::Marine, &Gender::Other),
-                            Gender::Other,
-                            0,
-                        );
-                        entity.ang = o.action.ang;
-                        worlds.lock().unwrap()[0].update_chunk_with_entity(entity);
-                    }
-                    ActionType::ConstructRoad => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        let mut entity = Entity::from(
-                            rng.gen_range(0..1000) as usize,
-                            coords,
-                            (0.0, 0.0, 0.0),
-                            EntityType::Road,
-                            Stats::gen(),
-                            Alignment::from(Faction::Marine),
-                            gen_human_name(Faction::Marine, &Gender::Other),
-                            Gender::Other,
-                            0,
-                        );
-                        entity.ang = o.action.ang;
-                        worlds.lock().unwrap()[0].update_chunk_with_entity(entity);
-                    }
-                    ActionType::ConstructLandmine => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        let mut entity = Entity::from(
-                            rng.gen_range(0..1000) as usize,
-                            coords,
-                            (0.0, 0.0, 0.0),
-                            EntityType::Landmine,
-                            Stats::gen(),
-                            Alignment::from(Faction::Marine),
-                            gen_human_name(Faction::Marine, &Gender::Other),
-                            Gender::Other,
-                            0,
-                        );
-                        entity.ang = o.action.ang;
-                        worlds.lock().unwrap()[0].update_chunk_with_entity(entity);
-                    }
-                    ActionType::ConstructShell => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        let mut entity = Entity::gen_shell(
-                            rng.gen_range(0..1000),
-                            coords.x.as_f32(),
-                            coords.y.as_f32(),
-                            coords.z.as_f32(),
-                        );
-                        entity.traj = entity.traj;
-                        entity.vel.x =
-                            HashableF32(o.action.ang.as_f32().sin() * 1.0) * HashableF32(1.0);
-                        entity.vel.y =
-                            HashableF32(-o.action.ang.as_f32().cos() * 1.0) * HashableF32(1.0);
-                        entity.vel.z =
-                            HashableF32(o.action.traj.as_f32().cos() * 1.0) * HashableF32(0.5);
-                        entity.ang = o.action.ang;
-                        worlds.lock().unwrap()[0].update_chunk_with_entity(entity);
+            // Players gone for longer than the grace period leave the world, asleep or for good.
+            // Detached stays locked until they are asleep, so a resume finds them in one or the other.
+            let mut commands = vec![];
+            let mut slept = false;
+            {
+                let mut detached = detached.lock().unwrap();
+                let expired: Vec<usize> = detached
+                    .iter()
+                    .filter(|(_, (_, since))| since.elapsed() >= grace)
+                    .map(|(index, _)| *index)
+                    .collect();
+                for index in expired {
+                    let (session, _) = detached.remove(&index).unwrap();
+                    let world = simulation.locate(index);
+                    let entity = world.and_then(|w| simulation.worlds[w].fetch_entity(index));
+                    if let (DisconnectAction::Sleep, Some(w), Some(entity)) =
+                        (&disconnect_action, world, entity)
+                    {
+                        let sleeper = Sleeper {
+                            session: session,
+                            world: w,
+                            entity: entity.clone(),
+                        };
+                        sleepers.lock().unwrap().insert(sleeper);
+                        slept = true;
                     }
-                    ActionType::ConstructCar => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        let mut entity = Entity::gen_car(
-                            rng.gen_range(0..1000),
-                            coords.x.as_f32(),
-                            coords.y.as_f32(),
-                            coords.z.as_f32(),
-                        );
-                        entity.traj = entity.traj;
-                        entity.vel.x =
-                            HashableF32(o.action.ang.as_f32().sin() * 1.0) * HashableF32(1.0);
-                        entity.vel.y =
-                            HashableF32(-o.action.ang.as_f32().cos() * 1.0) * HashableF32(1.0);
-                        entity.vel.z =
-                            HashableF32(o.action.traj.as_f32().cos() * 1.0) * HashableF32(0.5);
-                        entity.ang = o.action.ang;
-                        worlds.lock().unwrap()[0].update_chunk_with_entity(entity);
+                    commands.push(WorldCommand::Despawn(index));
+                }
+            }
+            if slept {
+                save_sleepers(&sleepers);
+            }
+            let now = started.elapsed();
+            let previous = snapshots.load_full();
+            let (input, snapshot) = match queue.tick(&mut simulation, commands, &previous, now) {
+                Some(tick) => tick,
+                None => break,
+            };
+            if let Some(r) = recorder.as_mut() {
+                if let Err(e) = r.record(&simulation, &input, now) {
+                    eprintln!("Stopped recording to {}: {}", r.path(), e);
+                    recorder = None;
+                }
+            }
+            if last_news.elapsed() >= *NEWS_INTERVAL {
+                last_news = Instant::now();
+                let mut chunks: Vec<(usize, usize)> = input
+                    .players
+                    .iter()
+                    .filter_map(|i| {
+                        let w = simulation.locate(*i)?;
+                        let e = simulation.worlds[w].fetch_entity(*i)?;
+                        Some((w, chunk_index(&e.ccoords)?))
+                    })
+                    .collect();
+                chunks.sort();
+                chunks.dedup();
+                for (w, index) in chunks {
+                    let chunk = simulation.worlds[w].fetch_chunk(index);
+                    let news = chunk.inquire_news().newscast;
+                    if reported_news.get(&(w, index)) == Some(&news) {
+                        continue;
                     }
-                    ActionType::Interact => {
-                        let mut coords = Coords_f32::new();
-                        coords.x = HashableF32(
-                            (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        coords.y = HashableF32(
-                            (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                * *TILE_SIZE as f32,
-                        );
-                        for mut e in &mut worlds.lock().unwrap()[0]
-                            .fetch_chunk_x_y_mut(
-                                o.entity.ccoords.x as f32,
-                                o.entity.ccoords.y as f32,
-                            )
-                            .entities
-                        {
-                            let mut coords_e = Coords_f32::new();
-                            coords_e.x = HashableF32(
-                                (e.coords.x.as_f32() / *TILE_SIZE as f32).floor()
-                                    * *TILE_SIZE as f32,
-                            );
-                            coords_e.y = HashableF32(
-                                (e.coords.y.as_f32() / *TILE_SIZE as f32).floor()
-                                    * *TILE_SIZE as f32,
-                            );
-                            if coords.x == coords_e.x && coords.y == coords_e.y {
-                                e.linked_entity_id = o.entity.index as u64;
-                            }
-                        }
+                    for line in &news {
+                        let _ = tx_chat.send(ChatEvent {
+                            message: ChatMessage::from(
+                                ChatChannel::Proximity(0),
+                                line.trim().to_string(),
+                            ),
+                            world: w,
+                            ccoords: chunk.coords.clone(),
+                        });
                     }
+                    reported_news.insert((w, index), news);
                 }
             }
+            snapshots.store(Arc::new(snapshot));
+            let _ = tx_tick.send(simulation.tick);
 
-            worlds
-                .lock()
-                .unwrap()
-                .par_iter_mut()
-                .for_each(|c| c.resolve(10.0, step_increment));
-            worlds
-                .lock()
-                .unwrap()
-                .par_iter_mut()
-                .for_each(|c| c.resolve_between(step_increment));
+            let rate = tick_rate.load(Ordering::Relaxed).max(1);
+            sleep(Duration::from_secs_f64(1.0 / rate as f64)).await;
+        }
+    });
 
-            if let Ok(o) = rx_c.try_recv() {
-                for mut e in &mut worlds.lock().unwrap()[0]
-                    .fetch_chunk_x_y_mut(o.entity.ccoords.x as f32, o.entity.ccoords.y as f32)
-                    .entities
-                {
-                    if e.linked_entity_id as u64 == o.entity.index as u64 {
-                        e.coords = o.entity.coords.clone();
-                    }
-                }
-                worlds.lock().unwrap()[0].update_chunk_with_entity(o.entity);
-            }
-            //let worlds_clone = worlds.clone();
-            if let Err(_) = tx.send(Arc::clone(&worlds)) {
-                println!("No active receivers left");
-            }
+    let shared = Shared {
+        inputs: inputs,
+        snapshots: snapshots_c,
+        ticks: rx_tick,
+        detached: detached_c,
+        sleepers: sleepers_c,
+        tick_rate: tick_rate_c,
+        chat: tx_chat_c,
+    };
+
+    let admin = admin::Admin::from(shared.clone(), config.save_dir.clone());
+    let admin_addr = config.admin_listen.parse().unwrap();
+    println!("Admin API on http://{}", config.admin_listen);
+    task::spawn(admin::serve(admin_addr, admin));
 
-            sleep(Duration::from_millis(1000 / 120)).await;
+    // Browsers and tools that cannot link the crates talk the same protocol over WebSocket
+    let ws_listener = TcpListener::bind(&config.websocket_listen).await.unwrap();
+    println!(
+        "Listening for WebSocket clients on {}",
+        config.websocket_listen
+    );
+    let ws_shared = shared.clone();
+    task::spawn(async move {
+        loop {
+            if let Ok((stream, _)) = ws_listener.accept().await {
+                task::spawn(handle_websocket(stream, ws_shared.clone()));
+            }
         }
     });
 
     // Start a TCP server
-    let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-    println!("Listening on 127.0.0.1:3000");
+    let listener = TcpListener::bind(&config.listen).await.unwrap();
+    println!("Listening on {}", config.listen);
 
     loop {
         if let Ok((stream, _)) = listener.accept().await {
-            let tx_c_clone = tx_c.clone();
-            let tx_c_a_clone = tx_c_a.clone();
-            let mut rx = _rx.clone();
+            task::spawn(handle_connection(stream, shared.clone()));
+        }
+    }
+}
+
+// What every connection shares with the simulation.
+#[derive(Clone)]
+struct Shared {
+    inputs: Inputs,
+    snapshots: Arc<ArcSwap<Snapshot>>,
+    ticks: watch::Receiver<u64>,
+    detached: Detached,
+    sleepers: Arc<Mutex<Sleepers>>,
+    tick_rate: Arc<AtomicU32>,
+    chat: broadcast::Sender<ChatEvent>,
+}
 
-            task::spawn(handle_connection(stream, tx_c_clone, tx_c_a_clone, rx));
+enum Reply {
+    Send(ServerMessage),
+    // Send, then drop the connection
+    Close(ServerMessage),
+}
+
+// Protocol state of one client, whichever transport it came in on.
+struct Peer {
+    shared: Shared,
+    // Capabilities this transport can offer
+    offered: Vec<Capability>,
+    handshake: Option<Handshake>,
+    view: View,
+    session: Option<Session>,
+    chat: broadcast::Receiver<ChatEvent>,
+}
+impl Peer {
+    fn from(shared: Shared, offered: Vec<Capability>) -> Peer {
+        Peer {
+            chat: shared.chat.subscribe(),
+            shared: shared,
+            offered: offered,
+            handshake: None,
+            view: View::new(),
+            session: None,
+        }
+    }
+    fn subscribed(&self) -> bool {
+        self.view.subscribed()
+    }
+    fn wants_chat(&self) -> bool {
+        self.session.is_some() && supports(&self.handshake, Capability::Chat)
+    }
+    // The chat message to pass on, if this peer is in the channel it was said on.
+    fn deliver(&self, event: &ChatEvent) -> Option<ServerMessage> {
+        let index = self.session.as_ref()?.entity_index;
+        // The sender already has it as the answer to its request
+        if event.message.sender == Some(index) {
+            return None;
+        }
+        let hears = match &event.message.channel {
+            ChatChannel::Global => true,
+            channel => {
+                let snapshot = self.shared.snapshots.load();
+                let entity = snapshot.worlds.get(event.world)?.fetch_entity(index)?;
+                match channel {
+                    ChatChannel::Proximity(r) => {
+                        let dx = entity.ccoords.x - event.ccoords.x;
+                        let dy = entity.ccoords.y - event.ccoords.y;
+                        dx * dx + dy * dy <= r * r
+                    }
+                    ChatChannel::Faction(faction) => entity.alignment.faction == *faction,
+                    ChatChannel::Global => true,
+                }
+            }
+        };
+        if !hears {
+            return None;
+        }
+        Some(ServerMessage::Chat(event.message.clone()))
+    }
+    fn chat(&mut self, message: ChatMessage) -> ServerMessage {
+        let snapshot = self.shared.snapshots.load();
+        match sign_chat(&snapshot, self.session.as_ref().unwrap(), message) {
+            Ok((message, world, ccoords)) => {
+                let _ = self.shared.chat.send(ChatEvent {
+                    message: message.clone(),
+                    world: world,
+                    ccoords: ccoords,
+                });
+                ServerMessage::ChatSent(message)
+            }
+            Err(e) => ServerMessage::Error(e),
+        }
+    }
+    // Whatever changed in the subscribed area since the last push, if anything did.
+    fn push(&mut self) -> Option<ServerMessage> {
+        let snapshot = self.shared.snapshots.load_full();
+        self.view.push(&snapshot, self.session.as_ref())
+    }
+    fn handle(&mut self, message: ClientMessage) -> Reply {
+        let response = match message {
+            ClientMessage::Handshake(h) => {
+                if h.version != *PROTOCOL_VERSION {
+                    return Reply::Close(ServerMessage::Error(ServerError::VersionMismatch {
+                        server: *PROTOCOL_VERSION,
+                        client: h.version,
+                    }));
+                }
+                if !h.dimensions.lines_up_with(dimensions()) {
+                    return Reply::Close(ServerMessage::Error(ServerError::DimensionMismatch {
+                        server: dimensions().clone(),
+                        client: h.dimensions,
+                    }));
+                }
+                let offer = Handshake {
+                    capabilities: self.offered.clone(),
+                    ..Handshake::new()
+                };
+                let negotiated = offer.negotiate(&h);
+                self.handshake = Some(negotiated.clone());
+                ServerMessage::Handshake(negotiated)
+            }
+            ClientMessage::Join(_)
+            | ClientMessage::Resume(_)
+            | ClientMessage::Data(_)
+            | ClientMessage::Subscribe(_)
+            | ClientMessage::Unsubscribe
+            | ClientMessage::Chat(_)
+                if self.handshake.is_none() =>
+            {
+                ServerMessage::Error(ServerError::HandshakeRequired)
+            }
+            ClientMessage::Join(_) if self.session.is_some() => {
+                ServerMessage::Joined(self.session.clone().unwrap())
+            }
+            ClientMessage::Join(requested) => {
+                let snapshot = self.shared.snapshots.load();
+                let joined = self.shared.inputs.join(next_entity_index(), requested, &snapshot);
+                self.session = Some(joined.clone());
+                ServerMessage::Joined(joined)
+            }
+            ClientMessage::Resume(_) if self.session.is_some() => {
+                ServerMessage::Joined(self.session.clone().unwrap())
+            }
+            ClientMessage::Resume(session) => self.resume(session),
+            ClientMessage::Data(_) if self.session.is_none() => {
+                ServerMessage::Error(ServerError::JoinRequired)
+            }
+            ClientMessage::Data(client_data)
+                if Some(client_data.session) != self.session.as_ref().map(|s| s.token) =>
+            {
+                ServerMessage::Error(ServerError::InvalidSession)
+            }
+            ClientMessage::Subscribe(_)
+                if !supports(&self.handshake, Capability::Subscriptions) =>
+            {
+                ServerMessage::Error(ServerError::Unsupported(Capability::Subscriptions))
+            }
+            ClientMessage::Subscribe(s) => {
+                let snapshot = self.shared.snapshots.load_full();
+                let reply = self.view.subscribe(&snapshot, self.session.as_ref(), s);
+                self.shared.ticks.borrow_and_update();
+                reply
+            }
+            ClientMessage::Unsubscribe => self.view.unsubscribe(&self.shared.snapshots.load()),
+            ClientMessage::Chat(_) if !supports(&self.handshake, Capability::Chat) => {
+                ServerMessage::Error(ServerError::Unsupported(Capability::Chat))
+            }
+            ClientMessage::Chat(_) if self.session.is_none() => {
+                ServerMessage::Error(ServerError::JoinRequired)
+            }
+            ClientMessage::Chat(message) => self.chat(message),
+            ClientMessage::Data(client_data) => self.view.data(
+                &self.shared.snapshots.load_full(),
+                &self.shared.inputs,
+                self.session.as_ref().unwrap(),
+                client_data,
+                supports(&self.handshake, Capability::DeltaChunks),
+            ),
+        };
+        Reply::Send(response)
+    }
+    // Takes back the entity of a session whose connection went away, whether it is still
+    // waiting in the world or has been put to sleep since.
+    fn resume(&mut self, session: Session) -> ServerMessage {
+        // One still waiting is taken out under the lock. Anything else the tick loop has already
+        // put to sleep, as it holds the lock while doing so.
+        let waiting = {
+            let mut detached = self.shared.detached.lock().unwrap();
+            match detached.get(&session.entity_index) {
+                Some((waiting, _)) if *waiting == session => {
+                    detached.remove(&session.entity_index);
+                    true
+                }
+                _ => false,
+            }
+        };
+        if !waiting {
+            let sleeper = match self.shared.sleepers.lock().unwrap().take(&session) {
+                Some(sleeper) => sleeper,
+                None => return ServerMessage::Error(ServerError::InvalidSession),
+            };
+            save_sleepers(&self.shared.sleepers);
+            // The server may have been restarted with fewer worlds since
+            let world = match sleeper.world {
+                w if w < self.shared.snapshots.load().worlds.len() => w,
+                _ => 0,
+            };
+            let _ = self
+                .shared
+                .inputs
+                .tx_w
+                .send(WorldCommand::Spawn(world, sleeper.entity));
+        }
+        self.shared
+            .inputs
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(session.entity_index, session.clone());
+        self.session = Some(session.clone());
+        ServerMessage::Joined(session)
+    }
+    // The entity stays where it is for the grace period, in case the connection comes back.
+    fn close(self) {
+        if let Some(session) = self.session {
+            self.shared
+                .inputs
+                .sessions
+                .lock()
+                .unwrap()
+                .remove(&session.entity_index);
+            self.shared
+                .detached
+                .lock()
+                .unwrap()
+                .insert(session.entity_index, (session, Instant::now()));
         }
     }
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
-    tx_c: Sender<ClientData>,
-    tx_c_a: Sender<ClientData>,
-    mut rx: Receiver<Arc<Mutex<Vec<World>>>>,
-) {
-    let mut buffer = [0; 65536];
+async fn handle_connection(stream: TcpStream, shared: Shared) {
+    let (reader, writer) = stream.into_split();
+    let mut reader = FrameReader::from(reader, *MAX_FRAME_SIZE);
+    let mut writer = FrameWriter::from(writer, *MAX_FRAME_SIZE);
+    let mut peer = Peer::from(shared, Capability::supported());
 
     loop {
-        let read_result = stream.read(&mut buffer).await;
-        let mut result_client_data: Option<ClientData> = None;
-        match read_result {
-            Ok(0) => break, // Connection closed
-            Ok(n) => {
-                // Deserialize the received data
-                let incoming_data: Result<ClientData, _> = bincode::deserialize(&buffer[..n]);
-
-                if let Ok(client_data) = incoming_data {
-                    result_client_data = Some(client_data.clone());
-                    let _ = tx_c.send(client_data.clone());
-                    let _ = tx_c_a.send(client_data);
-                } else if let Ok(action_data) = bincode::deserialize(&buffer[..n]) {
-                    let _ = tx_c_a.send(action_data);
-                } else {
-                    let client_data_error = bincode::deserialize::<ClientData>(&buffer[..n]).err();
-                    let action_data_error = bincode::deserialize::<ActionData>(&buffer[..n]).err();
-                    eprintln!(
-			"Failed to parse received data:\n - Raw bytes: {:?}\n - ClientData error: {:?}\n - ActionData error: {:?}",
-			&buffer[..n],
-			client_data_error,
-			action_data_error,
-		    );
+        let frame = tokio::select! {
+            frame = reader.read_frame() => match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break, // Connection closed
+                Err(e) => {
+                    eprintln!("Error reading from stream: {}", e);
+                    let _ = writer
+                        .write_message(&ServerMessage::Error(ServerError::Malformed(e.to_string())))
+                        .await;
+                    break;
+                }
+            },
+            Ok(()) = peer.shared.ticks.changed(), if peer.subscribed() => {
+                if let Some(push) = peer.push() {
+                    if let Err(e) = writer.write_message(&push).await {
+                        eprintln!("Error writing to stream: {}", e);
+                        break;
+                    }
                 }
+                continue;
+            }
+            event = peer.chat.recv(), if peer.wants_chat() => {
+                if let Some(chat) = event.ok().and_then(|e| peer.deliver(&e)) {
+                    if let Err(e) = writer.write_message(&chat).await {
+                        eprintln!("Error writing to stream: {}", e);
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+        let message: ClientMessage = match deserialize_frame(&frame) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to parse received data: {}", e);
+                let _ = writer
+                    .write_message(&ServerMessage::Error(ServerError::Malformed(e.to_string())))
+                    .await;
+                continue;
+            }
+        };
+        let (response, close) = match peer.handle(message) {
+            Reply::Send(response) => (response, false),
+            Reply::Close(response) => (response, true),
+        };
+        if let Err(e) = writer.write_message(&response).await {
+            eprintln!("Error writing to stream: {}", e);
+            break;
+        }
+        if close {
+            break;
+        }
+        // The handshake itself goes out uncompressed, everything after it uses the codec
+        if let ServerMessage::Handshake(negotiated) = &response {
+            reader.set_compression(negotiated.compression());
+            writer.set_compression(negotiated.compression());
+        }
+    }
+    peer.close();
+}
 
-                // Send back a response with the current world state
-                if let Ok(worlds) = rx.recv() {
-                    let c = result_client_data.unwrap();
-                    if c.entity.ccoords.x as f32 >= 0.0
-                        && c.entity.ccoords.y as f32 >= 0.0
-                        && (c.entity.ccoords.x as f32) < (*WORLD_SIZE as f32)
-                        && (c.entity.ccoords.y as f32) < (*WORLD_SIZE as f32)
-                    {
-                        match c.data_type {
-                            ClientDataType::Chunk | ClientDataType::Refresh => {
-				let x = c.ccoords.x;
-				let y = c.ccoords.y;
-                                let mut chunks: Vec<Chunk> = Vec::new();
-                                if x >= 0
-                                    && y >= 0
-                                    && (x as f32) < (*WORLD_SIZE as f32)
-                                    && (y as f32) < (*WORLD_SIZE as f32)
-                                {
-                                    chunks.push(
-                                        worlds.lock().unwrap()[0]
-                                            .fetch_chunk_x_y(x as f32, y as f32)
-                                            .clone(),
-                                    );
-                                }
-                                let serialized_chunks = bincode::serialize(&chunks).unwrap();
-                                let _ = stream.write_all(&serialized_chunks).await;
-                            }
-                        }
-                    } else {
-                        // Fallback to sending a default chunk
-                        let mut chunks = Vec::new();
-                        chunks.push(worlds.lock().unwrap()[0].fetch_chunk_x_y(0.0, 0.0).clone());
-                        let serialized_chunks = bincode::serialize(&chunks).unwrap();
-                        let _ = stream.write_all(&serialized_chunks).await;
+// Binary frames carry bincode and text frames carry JSON. Replies use the encoding of the
+// last frame the client sent.
+async fn handle_websocket(stream: TcpStream, shared: Shared) {
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(*MAX_FRAME_SIZE),
+        max_frame_size: Some(*MAX_FRAME_SIZE),
+        ..WebSocketConfig::default()
+    };
+    let mut socket = match accept_async_with_config(stream, Some(ws_config)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+    // Compression is left to the WebSocket layer
+    let offered = Capability::supported()
+        .into_iter()
+        .filter(|c| !matches!(c, Capability::Compression(_)))
+        .collect();
+    let mut peer = Peer::from(shared, offered);
+    let mut json = false;
+
+    loop {
+        let message = tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(message)) => message,
+                None => break, // Connection closed
+                Some(Err(e)) => {
+                    eprintln!("Error reading from websocket: {}", e);
+                    break;
+                }
+            },
+            Ok(()) = peer.shared.ticks.changed(), if peer.subscribed() => {
+                if let Some(push) = peer.push() {
+                    if let Err(e) = send_websocket(&mut socket, &push, json).await {
+                        eprintln!("Error writing to websocket: {}", e);
+                        break;
+                    }
+                }
+                continue;
+            }
+            event = peer.chat.recv(), if peer.wants_chat() => {
+                if let Some(chat) = event.ok().and_then(|e| peer.deliver(&e)) {
+                    if let Err(e) = send_websocket(&mut socket, &chat, json).await {
+                        eprintln!("Error writing to websocket: {}", e);
+                        break;
                     }
                 }
+                continue;
             }
+        };
+        let parsed = match message {
+            WsMessage::Binary(bytes) => {
+                json = false;
+                bincode::deserialize::<ClientMessage>(&bytes).map_err(|e| e.to_string())
+            }
+            WsMessage::Text(text) => {
+                json = true;
+                serde_json::from_str::<ClientMessage>(&text).map_err(|e| e.to_string())
+            }
+            WsMessage::Close(_) => break,
+            // Pings are answered by tungstenite
+            _ => continue,
+        };
+        let (response, close) = match parsed.map(|message| peer.handle(message)) {
+            Ok(Reply::Send(response)) => (response, false),
+            Ok(Reply::Close(response)) => (response, true),
             Err(e) => {
-                eprintln!("Error reading from stream: {}", e);
-                break;
+                eprintln!("Failed to parse received data: {}", e);
+                (ServerMessage::Error(ServerError::Malformed(e)), false)
             }
+        };
+        if let Err(e) = send_websocket(&mut socket, &response, json).await {
+            eprintln!("Error writing to websocket: {}", e);
+            break;
+        }
+        if close {
+            break;
         }
     }
+    peer.close();
+}
+
+async fn send_websocket(
+    socket: &mut WebSocketStream<TcpStream>,
+    message: &ServerMessage,
+    json: bool,
+) -> Result<(), String> {
+    let message = if json {
+        serde_json::to_string(message)
+            .map(WsMessage::Text)
+            .map_err(|e| format!("Failed to serialize message: {}", e))?
+    } else {
+        bincode::serialize(message)
+            .map(WsMessage::Binary)
+            .map_err(|e| format!("Failed to serialize message: {}", e))?
+    };
+    socket.send(message).await.map_err(|e| e.to_string())
+}
+
+fn supports(handshake: &Option<Handshake>, capability: Capability) -> bool {
+    handshake
+        .as_ref()
+        .map_or(false, |h| h.capabilities.contains(&capability))
 }
@@ -0,0 +1,336 @@
+use crate::config::WorldConfig;
+use crate::frame::{
+    deserialize_frame, serialize_frame, FrameDecoder, MAX_FRAME_SIZE, READ_BUFFER_SIZE,
+};
+use crate::simulation::{Simulation, TickInput};
+use crate::util::PROTOCOL_VERSION;
+use crate::worldgen::{dimensions, set_dimensions, Dimensions, World};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // A run cut short loses at most this much of its recording
+    static ref FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+}
+
+// Leads every recording: what it takes to build the worlds the inputs were fed to.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RecordingHeader {
+    pub version: u32,
+    pub dimensions: Dimensions,
+    pub worlds: Vec<WorldConfig>,
+    pub seed: u64,
+    // Hash of every chunk of every world as generated
+    pub hashes: Vec<Vec<u64>>,
+}
+
+// One tick: what went in, how long into the run, and the chunks whose hash it changed as
+// (world, chunk index, hash). Ticks where nothing went in and nothing changed are left out.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RecordedTick {
+    pub tick: u64,
+    pub time: Duration,
+    pub input: TickInput,
+    pub changed: Vec<(usize, usize, u64)>,
+}
+
+fn chunk_hashes(worlds: &[World]) -> Vec<Vec<u64>> {
+    worlds
+        .iter()
+        .map(|w| w.chunks.iter().map(|c| c.hash).collect())
+        .collect()
+}
+
+// The chunks whose hash is no longer the one in hashes, which is brought up to date.
+fn changed_hashes(hashes: &mut [Vec<u64>], worlds: &[World]) -> Vec<(usize, usize, u64)> {
+    let mut changed = vec![];
+    for (w, world) in worlds.iter().enumerate() {
+        for (i, chunk) in world.chunks.iter().enumerate() {
+            if hashes[w][i] != chunk.hash {
+                hashes[w][i] = chunk.hash;
+                changed.push((w, i, chunk.hash));
+            }
+        }
+    }
+    changed
+}
+
+fn frame<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    serialize_frame(value, *MAX_FRAME_SIZE)
+        .map_err(|e| format!("Failed to serialize recording: {}", e))
+}
+
+// Runs on its own thread so the disk never holds up a tick. Flushes at most FLUSH_INTERVAL
+// after a frame comes in, and once more when the recorder is dropped.
+fn write_frames(mut file: BufWriter<File>, frames: Receiver<Vec<u8>>) -> Result<(), String> {
+    let write = |e: io::Error| format!("Failed to write recording: {}", e);
+    let mut unflushed: Option<Instant> = None;
+    loop {
+        let received = match unflushed {
+            Some(since) => frames.recv_timeout(FLUSH_INTERVAL.saturating_sub(since.elapsed())),
+            None => frames.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(frame) => {
+                file.write_all(&frame).map_err(write)?;
+                unflushed.get_or_insert_with(Instant::now);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return file.flush().map_err(write),
+        }
+        if unflushed.map_or(false, |since| since.elapsed() >= *FLUSH_INTERVAL) {
+            file.flush().map_err(write)?;
+            unflushed = None;
+        }
+    }
+}
+
+// Writes what the simulation is fed, tick by tick, so a run can be repeated without clients.
+pub struct Recorder {
+    path: String,
+    frames: Option<Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<Result<(), String>>>,
+    hashes: Vec<Vec<u64>>,
+}
+impl Recorder {
+    // Has to be created before the first step.
+    pub fn create(
+        path: &str,
+        worlds: &[WorldConfig],
+        simulation: &Simulation,
+    ) -> Result<Recorder, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        let hashes = chunk_hashes(&simulation.worlds);
+        let header = RecordingHeader {
+            version: *PROTOCOL_VERSION,
+            dimensions: dimensions().clone(),
+            worlds: worlds.to_vec(),
+            seed: simulation.seed(),
+            hashes: hashes.clone(),
+        };
+        let header = frame(&header)?;
+        let (tx, rx) = channel();
+        tx.send(header).unwrap();
+        let file = BufWriter::new(file);
+        Ok(Recorder {
+            path: path.to_string(),
+            frames: Some(tx),
+            writer: Some(thread::spawn(move || write_frames(file, rx))),
+            hashes: hashes,
+        })
+    }
+    // The writer only hangs up after failing, the error it returned says why.
+    fn stopped(&mut self) -> String {
+        self.frames = None;
+        match self.writer.take().map(|w| w.join()) {
+            Some(Ok(Err(e))) => e,
+            _ => "Recording writer stopped".to_string(),
+        }
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    // Called after every step with what went into it.
+    pub fn record(
+        &mut self,
+        simulation: &Simulation,
+        input: &TickInput,
+        time: Duration,
+    ) -> Result<(), String> {
+        let changed = changed_hashes(&mut self.hashes, &simulation.worlds);
+        if changed.is_empty() && input.is_empty() {
+            return Ok(());
+        }
+        let tick = RecordedTick {
+            tick: simulation.tick,
+            time: time,
+            input: input.clone(),
+            changed: changed,
+        };
+        let frame = frame(&tick)?;
+        let sent = match &self.frames {
+            Some(frames) => frames.send(frame).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(self.stopped());
+        }
+        Ok(())
+    }
+}
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // Hanging up lets the writer flush what is left and finish
+        self.frames = None;
+        if let Some(Ok(Err(e))) = self.writer.take().map(|w| w.join()) {
+            eprintln!("{}: {}", self.path, e);
+        }
+    }
+}
+
+pub struct Replay {
+    file: File,
+    decoder: FrameDecoder,
+    pub header: RecordingHeader,
+}
+impl Replay {
+    pub fn open(path: &str) -> Result<Replay, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let mut replay = Replay {
+            file: file,
+            decoder: FrameDecoder::new(),
+            header: RecordingHeader {
+                version: 0,
+                dimensions: Dimensions::new(),
+                worlds: vec![],
+                seed: 0,
+                hashes: vec![],
+            },
+        };
+        replay.header = replay.next_frame()?.ok_or(format!("{} is empty", path))?;
+        if replay.header.version != *PROTOCOL_VERSION {
+            return Err(format!(
+                "{} was recorded with protocol {}, this is {}",
+                path, replay.header.version, *PROTOCOL_VERSION
+            ));
+        }
+        Ok(replay)
+    }
+    fn next_frame<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
+        let mut buffer = vec![0u8; *READ_BUFFER_SIZE];
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(payload)) => {
+                    return deserialize_frame(&payload)
+                        .map(Some)
+                        .map_err(|e| format!("Failed to parse recording: {}", e))
+                }
+                Ok(None) => {}
+                Err(e) => return Err(format!("Failed to read recording: {}", e)),
+            }
+            let n = self
+                .file
+                .read(&mut buffer)
+                .map_err(|e| format!("Failed to read recording: {}", e))?;
+            if n == 0 {
+                // A run cut short may leave half a tick at the end
+                return Ok(None);
+            }
+            self.decoder.push(&buffer[..n]);
+        }
+    }
+    pub fn next_tick(&mut self) -> Result<Option<RecordedTick>, String> {
+        self.next_frame()
+    }
+}
+
+// A chunk whose hash differs from the recorded one after a tick, tick 0 being the generated world.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub tick: u64,
+    pub world: usize,
+    pub chunk: usize,
+    pub recorded: u64,
+    pub replayed: u64,
+}
+
+pub struct ReplayReport {
+    pub ticks: u64,
+    // Every mismatch on the first tick that had any
+    pub mismatches: Vec<Mismatch>,
+}
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mismatches.first() {
+            None => write!(f, "Replayed {} ticks, every chunk hash matched", self.ticks),
+            Some(first) => {
+                writeln!(
+                    f,
+                    "Diverged on tick {} after {} ticks, {} chunks differ:",
+                    first.tick,
+                    self.ticks,
+                    self.mismatches.len()
+                )?;
+                for m in &self.mismatches {
+                    writeln!(
+                        f,
+                        "  world {} chunk {}: recorded {:016x}, replayed {:016x}",
+                        m.world, m.chunk, m.recorded, m.replayed
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Builds the recorded worlds from scratch and feeds them the recorded inputs with no clients or
+// sockets involved, comparing chunk hashes tick by tick. Stops at the first tick that differs.
+pub fn replay(path: &str) -> Result<ReplayReport, String> {
+    let mut replay = Replay::open(path)?;
+    let header = replay.header.clone();
+    set_dimensions(header.dimensions.clone()).map_err(|d| {
+        format!(
+            "Recording needs dimensions {:?}, already fixed to {:?}",
+            header.dimensions, d
+        )
+    })?;
+    let mut simulation = Simulation::from(&header.worlds, header.seed);
+    let mut recorded = header.hashes.clone();
+    let mut replayed = chunk_hashes(&simulation.worlds);
+    let mut report = ReplayReport {
+        ticks: 0,
+        mismatches: compare(0, &recorded, &replayed),
+    };
+    if !report.mismatches.is_empty() {
+        return Ok(report);
+    }
+    let empty = TickInput::new();
+    while let Some(tick) = replay.next_tick()? {
+        // Skipped ticks had no input and changed nothing
+        while simulation.tick < tick.tick {
+            let input = if simulation.tick + 1 == tick.tick {
+                &tick.input
+            } else {
+                &empty
+            };
+            simulation.step(input, tick.time);
+            report.ticks += 1;
+            changed_hashes(&mut replayed, &simulation.worlds);
+            if simulation.tick == tick.tick {
+                for (w, i, hash) in &tick.changed {
+                    recorded[*w][*i] = *hash;
+                }
+            }
+            report.mismatches = compare(simulation.tick, &recorded, &replayed);
+            if !report.mismatches.is_empty() {
+                return Ok(report);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn compare(tick: u64, recorded: &[Vec<u64>], replayed: &[Vec<u64>]) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    for (w, (a, b)) in recorded.iter().zip(replayed).enumerate() {
+        for (i, (recorded, replayed)) in a.iter().zip(b).enumerate() {
+            if recorded != replayed {
+                mismatches.push(Mismatch {
+                    tick: tick,
+                    world: w,
+                    chunk: i,
+                    recorded: *recorded,
+                    replayed: *replayed,
+                });
+            }
+        }
+    }
+    mismatches
+}
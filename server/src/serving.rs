@@ -0,0 +1,451 @@
+use crate::simulation::{Simulation, TickInput, WorldCommand};
+use crate::snapshot::{Snapshot, WorldSnapshot};
+use crate::util::{
+    ChatChannel, ChatMessage, ClientData, ClientDataType, ServerError, ServerMessage, Session,
+    Subscription,
+};
+use crate::worldgen::{
+    Chunk, ChunkUpdate, Coords_f32, Coords_i32, Entity, TileType, CHUNK_SIZE, TILE_SIZE, WORLD_SIZE,
+};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What the network server and the local one do alike: hand out player indices, run a tick on
+// what players sent since the last one and answer for the chunks in and around a view.
+
+lazy_static! {
+    pub static ref MAX_VIEW_DISTANCE: i32 = 8;
+    // Player indices start above anything worldgen hands out and count up one by one. What
+    // players build is numbered apart from them, from FIRST_SPAWNED_INDEX in the simulation.
+    pub static ref FIRST_PLAYER_INDEX: usize = 1_000_000;
+    // Clients drop chunks more than this many chunks past their view, so bases for deltas are
+    // kept as far out and no further
+    pub static ref RETAIN_MARGIN: i32 = 1;
+    pub static ref MAX_CHAT_LENGTH: usize = 256;
+    // How many tiles out from where a client asked to spawn the server looks for dry land
+    static ref SPAWN_SEARCH: i32 = 64;
+}
+
+pub type Sessions = Arc<Mutex<HashMap<usize, Session>>>;
+
+// What peers send the simulation through, cloned for every one of them.
+#[derive(Clone)]
+pub struct Inputs {
+    pub tx_c: Sender<ClientData>,
+    pub tx_c_a: Sender<ClientData>,
+    pub tx_w: Sender<WorldCommand>,
+    // Live sessions by entity index. Data from entities without one is dropped.
+    pub sessions: Sessions,
+}
+impl Inputs {
+    pub fn new() -> (Inputs, InputQueue) {
+        let (tx_c, rx_c) = channel::<ClientData>();
+        let (tx_c_a, rx_c_a) = channel::<ClientData>();
+        let (tx_w, rx_w) = channel::<WorldCommand>();
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let queue = InputQueue {
+            rx_c: rx_c,
+            rx_c_a: rx_c_a,
+            rx_w: rx_w,
+            sessions: Arc::clone(&sessions),
+        };
+        let inputs = Inputs {
+            tx_c: tx_c,
+            tx_c_a: tx_c_a,
+            tx_w: tx_w,
+            sessions: sessions,
+        };
+        (inputs, queue)
+    }
+    // The player spawns on the next tick, and only from then on is there an entity to act as.
+    pub fn join(&self, index: usize, requested: Entity, snapshot: &Snapshot) -> Session {
+        // Tokens stay below 2^53 so JSON clients in JavaScript read them back exactly
+        let joined = Session::from(index, rand::random::<u64>() >> 11);
+        let (x, y, z) = spawn_point(&snapshot.worlds[0], &requested.coords);
+        let mut entity = Entity::gen_player(index, x, y, z);
+        entity.name = requested.name;
+        self.sessions.lock().unwrap().insert(index, joined.clone());
+        // Everyone starts out in the first world
+        let _ = self.tx_w.send(WorldCommand::Spawn(0, entity));
+        joined
+    }
+}
+
+// Clients only suggest where to spawn. The spot is pulled inside the world and onto the nearest
+// tile that can be walked on, at that tile's height.
+fn spawn_point(world: &WorldSnapshot, requested: &Coords_f32) -> (f32, f32, f32) {
+    let size = *TILE_SIZE as f32;
+    let last = (*WORLD_SIZE * *CHUNK_SIZE) as i32 - 1;
+    let x = ((requested.x.as_f32() / size).floor() as i32).clamp(0, last);
+    let y = ((requested.y.as_f32() / size).floor() as i32).clamp(0, last);
+    for r in 0..=*SPAWN_SEARCH {
+        for dy in -r..=r {
+            for dx in -r..=r {
+                // Only the ring r tiles out, the inside was searched already
+                if dx.abs() != r && dy.abs() != r {
+                    continue;
+                }
+                match world.fetch_tile(x + dx, y + dy) {
+                    Some(t) if t.ttype != TileType::Water => {
+                        return (
+                            (x + dx) as f32 * size,
+                            (y + dy) as f32 * size,
+                            t.coords.z as f32,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    // Nothing dry anywhere near, so at least stay inside the world
+    let z = world.fetch_tile(x, y).map_or(0.0, |t| t.coords.z as f32);
+    (x as f32 * size, y as f32 * size, z)
+}
+
+// The simulation's end of Inputs.
+pub struct InputQueue {
+    rx_c: Receiver<ClientData>,
+    rx_c_a: Receiver<ClientData>,
+    rx_w: Receiver<WorldCommand>,
+    sessions: Sessions,
+}
+impl InputQueue {
+    // Steps the simulation on everything sent since the last tick, after the given commands, and
+    // makes the snapshot that follows previous. None once every Inputs is gone.
+    pub fn tick(
+        &self,
+        simulation: &mut Simulation,
+        mut commands: Vec<WorldCommand>,
+        previous: &Snapshot,
+        now: Duration,
+    ) -> Option<(TickInput, Snapshot)> {
+        loop {
+            match self.rx_w.try_recv() {
+                Ok(command) => commands.push(command),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+        let input = TickInput {
+            commands: commands,
+            actions: self
+                .rx_c_a
+                .try_iter()
+                .filter(|o| self.has_session(o))
+                .collect(),
+            movements: latest_movements(self.rx_c.try_iter().filter(|o| self.has_session(o))),
+            players: self.sessions.lock().unwrap().keys().cloned().collect(),
+        };
+        simulation.step(&input, now);
+        let snapshot = previous.next(simulation.tick, &simulation.worlds, &simulation.locations);
+        Some((input, snapshot))
+    }
+    fn has_session(&self, client_data: &ClientData) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .contains_key(&client_data.entity.index)
+    }
+}
+
+// What a peer is shown: the world its entity is in, what it subscribed to, and the last version
+// of every chunk it was sent, used as the base for deltas.
+pub struct View {
+    // The world sent_chunks came from
+    world: usize,
+    sent_chunks: HashMap<usize, Chunk>,
+    subscription: Option<Subscription>,
+}
+impl View {
+    pub fn new() -> View {
+        View {
+            world: 0,
+            sent_chunks: HashMap::new(),
+            subscription: None,
+        }
+    }
+    pub fn subscribed(&self) -> bool {
+        self.subscription.is_some()
+    }
+    // The world shown is the one the entity of the session is in, the first one until it has
+    // joined. Chunks sent from another world are no base for deltas, so a move starts them over.
+    pub fn follow_world(&mut self, snapshot: &Snapshot, session: Option<&Session>) -> usize {
+        let world = session
+            .and_then(|s| snapshot.locate(s.entity_index))
+            .unwrap_or(self.world);
+        if world != self.world {
+            self.world = world;
+            self.sent_chunks.clear();
+            if let Some(subscription) = self.subscription.as_mut() {
+                let followed = subscription
+                    .follow
+                    .and_then(|i| snapshot.worlds[world].fetch_entity(i));
+                if let Some(e) = followed {
+                    subscription.ccoords = e.ccoords.clone();
+                }
+            }
+        }
+        world
+    }
+    // Whatever changed in the subscribed area since the last push, if anything did.
+    pub fn push(
+        &mut self,
+        snapshot: &Snapshot,
+        session: Option<&Session>,
+    ) -> Option<ServerMessage> {
+        self.subscription.as_ref()?;
+        let world = self.follow_world(snapshot, session);
+        let updates = push_updates(
+            &snapshot.worlds[world],
+            self.subscription.as_mut()?,
+            &mut self.sent_chunks,
+        );
+        // Pushes go by what was sent alone, so it has to match what the client still holds
+        let subscription = self.subscription.as_ref()?;
+        let (w, h) = extent(&subscription.area);
+        retain_around(
+            &mut self.sent_chunks,
+            &subscription.ccoords,
+            w + *RETAIN_MARGIN,
+            h + *RETAIN_MARGIN,
+        );
+        if updates.is_empty() {
+            return None;
+        }
+        Some(ServerMessage::Push(snapshot.tick, updates))
+    }
+    // Starts from full chunks; later ticks only push what changed since.
+    pub fn subscribe(
+        &mut self,
+        snapshot: &Snapshot,
+        session: Option<&Session>,
+        s: Subscription,
+    ) -> ServerMessage {
+        if !chunk_in_bounds(&s.ccoords) {
+            return ServerMessage::Error(ServerError::OutOfBounds(s.ccoords.clone()));
+        }
+        let world = self.follow_world(snapshot, session);
+        let chunks = fetch_chunks(
+            &snapshot.worlds[world],
+            &clamp_area(&s.area).chunk_coords(&s.ccoords),
+        );
+        self.sent_chunks.clear();
+        self.subscription = Some(s);
+        ServerMessage::ChunkUpdates(
+            snapshot.tick,
+            chunk_updates(chunks, &[], &mut self.sent_chunks),
+        )
+    }
+    pub fn unsubscribe(&mut self, snapshot: &Snapshot) -> ServerMessage {
+        self.subscription = None;
+        ServerMessage::ChunkUpdates(snapshot.tick, vec![])
+    }
+    // Whatever index and world the client claims, it can only act as its own entity. The answer
+    // comes from the latest published world state, with chunks as deltas if the peer takes them.
+    pub fn data(
+        &mut self,
+        snapshot: &Snapshot,
+        inputs: &Inputs,
+        session: &Session,
+        mut client_data: ClientData,
+        deltas: bool,
+    ) -> ServerMessage {
+        client_data.entity.index = session.entity_index;
+        client_data.entity.current_world = self.follow_world(snapshot, Some(session));
+        let _ = inputs.tx_c.send(client_data.clone());
+        let _ = inputs.tx_c_a.send(client_data.clone());
+        match respond(
+            &snapshot.worlds[client_data.entity.current_world],
+            &client_data,
+        ) {
+            ServerMessage::Chunks(chunks) if deltas => {
+                let updates =
+                    chunk_updates(chunks, &client_data.known_chunks, &mut self.sent_chunks);
+                // Deltas here are only made against hashes the client reports, so anything a view
+                // around the same centre could reach can stay
+                let reach = *MAX_VIEW_DISTANCE + *RETAIN_MARGIN;
+                retain_around(&mut self.sent_chunks, &client_data.ccoords, reach, reach);
+                ServerMessage::ChunkUpdates(snapshot.tick, updates)
+            }
+            response => response,
+        }
+    }
+}
+
+// A chat message as it goes out from the entity of a session: trimmed and cut to length, signed
+// with the sender's name, and on a channel it may use. Comes with the world and chunk it was
+// said in, for picking who hears it.
+pub fn sign_chat(
+    snapshot: &Snapshot,
+    session: &Session,
+    mut message: ChatMessage,
+) -> Result<(ChatMessage, usize, Coords_i32), ServerError> {
+    let index = session.entity_index;
+    let world = snapshot.locate(index).unwrap_or(0);
+    let entity = match snapshot.worlds[world].fetch_entity(index) {
+        Some(entity) => entity,
+        // Joined, but not spawned until the next tick
+        None => return Err(ServerError::JoinRequired),
+    };
+    let text: String = message.text.trim().chars().take(*MAX_CHAT_LENGTH).collect();
+    if text.is_empty() {
+        return Err(ServerError::Malformed("Empty chat message".to_string()));
+    }
+    message.channel = match message.channel {
+        ChatChannel::Proximity(r) => ChatChannel::Proximity(r.clamp(0, *MAX_VIEW_DISTANCE)),
+        ChatChannel::Faction(_) => ChatChannel::Faction(entity.alignment.faction.clone()),
+        ChatChannel::Global => ChatChannel::Global,
+    };
+    message.sender = Some(index);
+    message.name = entity.name.clone();
+    message.text = text;
+    Ok((message, world, entity.ccoords.clone()))
+}
+
+// Each tick steps an entity once at most, so of the movements sent since the last one only the
+// latest of each entity counts. They keep the order they first arrived in.
+fn latest_movements(movements: impl Iterator<Item = ClientData>) -> Vec<ClientData> {
+    let mut latest: Vec<ClientData> = vec![];
+    for movement in movements {
+        match latest
+            .iter_mut()
+            .find(|o| o.entity.index == movement.entity.index)
+        {
+            Some(o) => *o = movement,
+            None => latest.push(movement),
+        }
+    }
+    latest
+}
+
+// Chunks the client already holds unchanged are left out, changed ones are sent as deltas
+// against the version this connection last sent.
+fn chunk_updates(
+    chunks: Vec<Chunk>,
+    known_chunks: &[(usize, u64)],
+    sent_chunks: &mut HashMap<usize, Chunk>,
+) -> Vec<ChunkUpdate> {
+    let mut updates = vec![];
+    for chunk in chunks {
+        let known_hash = known_chunks
+            .iter()
+            .find(|(index, _)| *index == chunk.index)
+            .map(|(_, hash)| *hash);
+        let update = match sent_chunks
+            .get(&chunk.index)
+            .filter(|base| Some(base.hash) == known_hash)
+        {
+            Some(base) if base.hash == chunk.hash => continue,
+            Some(base) => match chunk.diff(base) {
+                Some(delta) => ChunkUpdate::Delta(delta),
+                None => ChunkUpdate::Full(chunk.clone()),
+            },
+            None => ChunkUpdate::Full(chunk.clone()),
+        };
+        updates.push(update);
+        sent_chunks.insert(chunk.index, chunk);
+    }
+    updates
+}
+
+fn chunk_in_bounds(ccoords: &Coords_i32) -> bool {
+    ccoords.x >= 0
+        && ccoords.y >= 0
+        && (ccoords.x as f32) < (*WORLD_SIZE as f32)
+        && (ccoords.y as f32) < (*WORLD_SIZE as f32)
+}
+
+fn respond(world: &WorldSnapshot, c: &ClientData) -> ServerMessage {
+    if !chunk_in_bounds(&c.entity.ccoords) {
+        return ServerMessage::Error(ServerError::OutOfBounds(c.entity.ccoords.clone()));
+    }
+    match c.data_type {
+        ClientDataType::Chunk | ClientDataType::Refresh => {
+            if !chunk_in_bounds(&c.ccoords) {
+                return ServerMessage::Error(ServerError::OutOfBounds(c.ccoords.clone()));
+            }
+            ServerMessage::Chunks(vec![world
+                .fetch_chunk_x_y(c.ccoords.x as f32, c.ccoords.y as f32)
+                .clone()])
+        }
+        ClientDataType::Rect { .. } | ClientDataType::Radius(_) => ServerMessage::Chunks(
+            fetch_chunks(world, &clamp_area(&c.data_type).chunk_coords(&c.ccoords)),
+        ),
+    }
+}
+
+fn clamp_area(data_type: &ClientDataType) -> ClientDataType {
+    match data_type {
+        ClientDataType::Rect { w, h } => ClientDataType::Rect {
+            w: (*w).clamp(0, *MAX_VIEW_DISTANCE),
+            h: (*h).clamp(0, *MAX_VIEW_DISTANCE),
+        },
+        ClientDataType::Radius(r) => ClientDataType::Radius((*r).clamp(0, *MAX_VIEW_DISTANCE)),
+        data_type => data_type.clone(),
+    }
+}
+
+// The subscribed area moves with the followed entity. Everything already pushed counts as known,
+// so only chunks that changed since the last push go out.
+fn push_updates(
+    world: &WorldSnapshot,
+    subscription: &mut Subscription,
+    sent_chunks: &mut HashMap<usize, Chunk>,
+) -> Vec<ChunkUpdate> {
+    if let Some(index) = subscription.follow {
+        let area = clamp_area(&subscription.area).chunk_coords(&subscription.ccoords);
+        let followed = area
+            .iter()
+            .filter(|c| chunk_in_bounds(c))
+            .flat_map(|c| {
+                world
+                    .fetch_chunk_x_y(c.x as f32, c.y as f32)
+                    .entities
+                    .iter()
+            })
+            .find(|e| e.index == index);
+        if let Some(e) = followed {
+            if chunk_in_bounds(&e.ccoords) {
+                subscription.ccoords = e.ccoords.clone();
+            }
+        }
+    }
+    let area = clamp_area(&subscription.area).chunk_coords(&subscription.ccoords);
+    let known = known_chunks(sent_chunks);
+    chunk_updates(fetch_chunks(world, &area), &known, sent_chunks)
+}
+
+// How many chunks an area reaches out from its centre, across and down.
+fn extent(area: &ClientDataType) -> (i32, i32) {
+    match clamp_area(area) {
+        ClientDataType::Rect { w, h } => (w, h),
+        ClientDataType::Radius(r) => (r, r),
+        ClientDataType::Chunk | ClientDataType::Refresh => (0, 0),
+    }
+}
+
+// Forgets what was sent further than w and h chunks from ccoords.
+fn retain_around(sent_chunks: &mut HashMap<usize, Chunk>, ccoords: &Coords_i32, w: i32, h: i32) {
+    sent_chunks
+        .retain(|_, c| (c.coords.x - ccoords.x).abs() <= w && (c.coords.y - ccoords.y).abs() <= h);
+}
+
+fn known_chunks(sent_chunks: &HashMap<usize, Chunk>) -> Vec<(usize, u64)> {
+    sent_chunks
+        .iter()
+        .map(|(index, chunk)| (*index, chunk.hash))
+        .collect()
+}
+
+// Coordinates past the world edge are skipped, so a view near the border just comes back smaller.
+fn fetch_chunks(world: &WorldSnapshot, coords: &[Coords_i32]) -> Vec<Chunk> {
+    coords
+        .iter()
+        .filter(|c| chunk_in_bounds(c))
+        .map(|c| world.fetch_chunk_x_y(c.x as f32, c.y as f32).clone())
+        .collect()
+}
@@ -0,0 +1,441 @@
+use crate::config::{Generator, WorldConfig};
+use crate::util::{step_entity, ActionType, ClientData, MovementIntent, MOVE_INTERVAL};
+use crate::worldgen::*;
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+lazy_static! {
+    pub static ref STEP_INCREMENT: i32 = 1;
+    // What players build is numbered from here up, well above worldgen and the players themselves
+    pub static ref FIRST_SPAWNED_INDEX: usize = 1_000_000_000;
+    // and what the admin API places from here, far enough up that building never reaches it
+    pub static ref FIRST_PLACED_INDEX: usize = 2_000_000_000;
+}
+
+// Connections never touch the worlds directly, changes are queued for the next tick.
+// Spawn names the world by its index, the others find the entity in whichever world has it.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum WorldCommand {
+    Spawn(usize, Entity),
+    Despawn(usize),
+    // Entity index, the world to move it to and where it lands there
+    Transfer(usize, usize, Coords_f32),
+}
+
+// Everything that reached the simulation on one tick. Together with the time it happened at,
+// this is all a tick depends on, so feeding the same inputs to the same worlds repeats it.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TickInput {
+    pub commands: Vec<WorldCommand>,
+    pub actions: Vec<ClientData>,
+    pub movements: Vec<ClientData>,
+    // Entities of the players with a session, whose chunks keep running whether they move or not
+    pub players: Vec<usize>,
+}
+impl TickInput {
+    pub fn new() -> TickInput {
+        TickInput {
+            commands: vec![],
+            actions: vec![],
+            movements: vec![],
+            players: vec![],
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+            && self.actions.is_empty()
+            && self.movements.is_empty()
+            && self.players.is_empty()
+    }
+}
+
+// The hosted worlds and what it takes to advance them a tick.
+pub struct Simulation {
+    pub worlds: Vec<World>,
+    pub tick: u64,
+    // Where everything placed by command is, so players are found without searching every world
+    pub locations: HashMap<usize, usize>,
+    // When each entity last moved, as time since the simulation started
+    last_moves: HashMap<usize, Duration>,
+    seed: u64,
+    // Index of the next entity a player builds
+    spawned: usize,
+}
+impl Simulation {
+    pub fn from(worlds: &[WorldConfig], seed: u64) -> Simulation {
+        Simulation {
+            worlds: worlds
+                .iter()
+                .enumerate()
+                .map(|(i, w)| generate(i, w))
+                .collect(),
+            tick: 0,
+            locations: HashMap::new(),
+            last_moves: HashMap::new(),
+            seed: seed,
+            spawned: *FIRST_SPAWNED_INDEX,
+        }
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    pub fn locate(&self, index: usize) -> Option<usize> {
+        locate(&self.worlds, &self.locations, index)
+    }
+    fn next_index(&mut self) -> usize {
+        self.spawned += 1;
+        self.spawned - 1
+    }
+    // now is the time since the simulation started.
+    pub fn step(&mut self, input: &TickInput, now: Duration) {
+        // Whatever is rolled this tick follows from the seed and the tick alone
+        reseed(self.seed, self.tick);
+        for command in input.commands.iter().cloned() {
+            match command {
+                WorldCommand::Spawn(w, mut entity) => {
+                    if let Some(world) = self.worlds.get_mut(w) {
+                        entity.current_world = w;
+                        self.locations.insert(entity.index, w);
+                        world.update_chunk_with_entity(entity);
+                    }
+                }
+                WorldCommand::Despawn(index) => {
+                    if let Some(w) = locate(&self.worlds, &self.locations, index) {
+                        self.worlds[w].remove_entity(index);
+                    }
+                    self.locations.remove(&index);
+                }
+                WorldCommand::Transfer(index, to, coords) => {
+                    transfer(&mut self.worlds, &mut self.locations, index, to, coords);
+                }
+            }
+        }
+        // Actions happen where the server has the entity, whatever the client claims
+        let actions: Vec<(usize, ClientData)> = input
+            .actions
+            .iter()
+            .cloned()
+            .filter_map(|mut o| {
+                let w = locate(&self.worlds, &self.locations, o.entity.index)?;
+                let entity = self.worlds[w].fetch_entity(o.entity.index)?.clone();
+                o.entity = Entity {
+                    current_action: o.action.action_type.clone(),
+                    ..entity
+                };
+                Some((w, o))
+            })
+            .collect();
+        for (w, o) in actions {
+            match o.entity.current_action {
+                ActionType::Empty => {}
+                ActionType::Refresh => {}
+                ActionType::ConstructCannon => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let mut entity = Entity::from(
+                        self.next_index(),
+                        coords,
+                        (0.0, 0.0, 0.0),
+                        EntityType::Cannon,
+                        Stats::gen(),
+                        Alignment::from(Faction::Marine),
+                        gen_human_name(Faction::Marine, &Gender::Other),
+                        Gender::Other,
+                        w,
+                    );
+                    entity.ang = o.action.ang;
+                    self.worlds[w].update_chunk_with_entity(entity);
+                }
+                ActionType::ConstructRoad => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let mut entity = Entity::from(
+                        self.next_index(),
+                        coords,
+                        (0.0, 0.0, 0.0),
+                        EntityType::Road,
+                        Stats::gen(),
+                        Alignment::from(Faction::Marine),
+                        gen_human_name(Faction::Marine, &Gender::Other),
+                        Gender::Other,
+                        w,
+                    );
+                    entity.ang = o.action.ang;
+                    self.worlds[w].update_chunk_with_entity(entity);
+                }
+                ActionType::ConstructLandmine => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let mut entity = Entity::from(
+                        self.next_index(),
+                        coords,
+                        (0.0, 0.0, 0.0),
+                        EntityType::Landmine,
+                        Stats::gen(),
+                        Alignment::from(Faction::Marine),
+                        gen_human_name(Faction::Marine, &Gender::Other),
+                        Gender::Other,
+                        w,
+                    );
+                    entity.ang = o.action.ang;
+                    self.worlds[w].update_chunk_with_entity(entity);
+                }
+                ActionType::ConstructShell => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let mut entity = Entity::gen_shell(
+                        self.next_index(),
+                        coords.x.as_f32(),
+                        coords.y.as_f32(),
+                        coords.z.as_f32(),
+                    );
+                    entity.traj = entity.traj;
+                    entity.current_world = w;
+                    entity.vel.x =
+                        HashableF32(o.action.ang.as_f32().sin() * 1.0) * HashableF32(1.0);
+                    entity.vel.y =
+                        HashableF32(-o.action.ang.as_f32().cos() * 1.0) * HashableF32(1.0);
+                    entity.vel.z =
+                        HashableF32(o.action.traj.as_f32().cos() * 1.0) * HashableF32(0.5);
+                    entity.ang = o.action.ang;
+                    self.worlds[w].update_chunk_with_entity(entity);
+                }
+                ActionType::ConstructCar => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let mut entity = Entity::gen_car(
+                        self.next_index(),
+                        coords.x.as_f32(),
+                        coords.y.as_f32(),
+                        coords.z.as_f32(),
+                    );
+                    entity.traj = entity.traj;
+                    entity.current_world = w;
+                    entity.vel.x =
+                        HashableF32(o.action.ang.as_f32().sin() * 1.0) * HashableF32(1.0);
+                    entity.vel.y =
+                        HashableF32(-o.action.ang.as_f32().cos() * 1.0) * HashableF32(1.0);
+                    entity.vel.z =
+                        HashableF32(o.action.traj.as_f32().cos() * 1.0) * HashableF32(0.5);
+                    entity.ang = o.action.ang;
+                    self.worlds[w].update_chunk_with_entity(entity);
+                }
+                ActionType::Interact => {
+                    let mut coords = Coords_f32::new();
+                    coords.x = HashableF32(
+                        (o.entity.coords.x.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    coords.y = HashableF32(
+                        (o.entity.coords.y.as_f32() / *TILE_SIZE as f32).floor()
+                            * *TILE_SIZE as f32,
+                    );
+                    let chunk = self.worlds[w]
+                        .fetch_chunk_x_y_mut(o.entity.ccoords.x as f32, o.entity.ccoords.y as f32);
+                    for mut e in &mut chunk.entities {
+                        let mut coords_e = Coords_f32::new();
+                        coords_e.x = HashableF32(
+                            (e.coords.x.as_f32() / *TILE_SIZE as f32).floor() * *TILE_SIZE as f32,
+                        );
+                        coords_e.y = HashableF32(
+                            (e.coords.y.as_f32() / *TILE_SIZE as f32).floor() * *TILE_SIZE as f32,
+                        );
+                        // Portals stay linked to their counterpart
+                        if coords.x == coords_e.x
+                            && coords.y == coords_e.y
+                            && e.etype != EntityType::Portal
+                        {
+                            e.linked_entity_id = o.entity.index as u64;
+                        }
+                    }
+                    // Snapshots only pick up chunks whose hash moved
+                    chunk.rehash_entities();
+                }
+            }
+        }
+
+        // Only observed chunks resolve, and a player standing still is still watching theirs
+        for index in &input.players {
+            let found = locate(&self.worlds, &self.locations, *index).and_then(|w| {
+                let ccoords = &self.worlds[w].fetch_entity(*index)?.ccoords;
+                Some((w, chunk_index(ccoords)?))
+            });
+            if let Some((w, i)) = found {
+                self.worlds[w].chunks[i].observed = true;
+            }
+        }
+        self.worlds
+            .par_iter_mut()
+            .for_each(|c| c.resolve(10.0, *STEP_INCREMENT));
+        self.worlds
+            .par_iter_mut()
+            .for_each(|c| c.resolve_between(*STEP_INCREMENT));
+
+        for o in &input.movements {
+            let rested = self
+                .last_moves
+                .get(&o.entity.index)
+                .map_or(true, |t| now.saturating_sub(*t) >= *MOVE_INTERVAL);
+            let w = locate(&self.worlds, &self.locations, o.entity.index).unwrap_or(0);
+            let moved = match self.worlds[w].fetch_entity(o.entity.index) {
+                // Inputs that come too soon are turned down, but still acknowledged so the
+                // client stops replaying them
+                Some(entity) if o.intent.is_moving() => {
+                    let mut moved = if rested {
+                        move_entity(&self.worlds[w], entity, &o.intent)
+                    } else {
+                        entity.clone()
+                    };
+                    moved.input_sequence = entity.input_sequence.max(o.sequence);
+                    Some((tile_of(&moved.coords) != tile_of(&entity.coords), moved))
+                }
+                _ => None,
+            };
+            if let Some((stepped, entity)) = moved {
+                if rested {
+                    self.last_moves.insert(entity.index, now);
+                }
+                for mut e in &mut self.worlds[w]
+                    .fetch_chunk_x_y_mut(entity.ccoords.x as f32, entity.ccoords.y as f32)
+                    .entities
+                {
+                    if e.linked_entity_id as u64 == entity.index as u64 {
+                        e.coords = entity.coords.clone();
+                    }
+                }
+                // Stepping onto a portal carries on to wherever its counterpart stands
+                let index = entity.index;
+                let destination = portal_at(&self.worlds[w], &entity.coords)
+                    .filter(|_| stepped)
+                    .and_then(|target| {
+                        let to = locate(&self.worlds, &self.locations, target)?;
+                        Some((to, self.worlds[to].fetch_entity(target)?.coords.clone()))
+                    });
+                self.worlds[w].update_chunk_with_entity(entity);
+                if let Some((to, coords)) = destination {
+                    transfer(&mut self.worlds, &mut self.locations, index, to, coords);
+                }
+            }
+        }
+        self.tick += 1;
+    }
+}
+
+fn move_entity(world: &World, entity: &Entity, intent: &MovementIntent) -> Entity {
+    step_entity(entity, intent, |x, y| world.fetch_tile(x, y))
+}
+
+// Generators put everything in the first world, so the entities of later ones are told otherwise.
+fn generate(index: usize, config: &WorldConfig) -> World {
+    let mut world = match config.generator {
+        Generator::Worldgen => worldgen(config.seed),
+        Generator::Globegen => globegen(config.map.as_ref().unwrap()),
+    };
+    if index > 0 {
+        for chunk in &mut world.chunks {
+            for e in &mut chunk.entities {
+                e.current_world = index;
+            }
+            chunk.rehash_entities();
+        }
+    }
+    world
+}
+
+// Entities placed by command are looked up directly, anything else is searched for.
+fn locate(worlds: &[World], locations: &HashMap<usize, usize>, index: usize) -> Option<usize> {
+    match locations.get(&index) {
+        Some(w) => Some(*w),
+        None => worlds.iter().position(|w| w.fetch_entity(index).is_some()),
+    }
+}
+
+// Takes an entity out of whichever world has it and puts it down in another, on top of the
+// tile it lands on. Nothing happens if the destination does not exist.
+fn transfer(
+    worlds: &mut [World],
+    locations: &mut HashMap<usize, usize>,
+    index: usize,
+    to: usize,
+    coords: Coords_f32,
+) {
+    let ccoords = ccoords_of(&coords);
+    if to >= worlds.len() || chunk_index(&ccoords).is_none() {
+        return;
+    }
+    let mut entity =
+        match locate(worlds, locations, index).and_then(|from| worlds[from].remove_entity(index)) {
+            Some(entity) => entity,
+            None => return,
+        };
+    let (x, y) = tile_of(&coords);
+    entity.coords = coords;
+    if let Some(tile) = worlds[to].fetch_tile(x, y) {
+        entity.coords.z = HashableF32(tile.coords.z as f32);
+    }
+    entity.ccoords = ccoords;
+    entity.current_world = to;
+    locations.insert(index, to);
+    worlds[to].update_chunk_with_entity(entity);
+}
+
+// The counterpart of a portal standing on the same tile, if there is one.
+fn portal_at(world: &World, coords: &Coords_f32) -> Option<usize> {
+    world
+        .fetch_chunk(chunk_index(&ccoords_of(coords))?)
+        .entities
+        .iter()
+        .find(|e| e.etype == EntityType::Portal && tile_of(&e.coords) == tile_of(coords))
+        .map(|e| e.linked_entity_id as usize)
+}
+
+fn tile_of(coords: &Coords_f32) -> (i32, i32) {
+    (
+        (coords.x / HashableF32(*TILE_SIZE as f32)).as_f32().floor() as i32,
+        (coords.y / HashableF32(*TILE_SIZE as f32)).as_f32().floor() as i32,
+    )
+}
+
+fn ccoords_of(coords: &Coords_f32) -> Coords_i32 {
+    let (x, y) = tile_of(coords);
+    let size = *CHUNK_SIZE as i32;
+    Coords_i32::from((x.div_euclid(size), y.div_euclid(size), 0))
+}
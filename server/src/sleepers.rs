@@ -0,0 +1,99 @@
+use dimensioner_server::util::Session;
+use dimensioner_server::worldgen::Entity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// A player taken out of the world after disconnecting, kept until its session resumes.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Sleeper {
+    pub session: Session,
+    pub world: usize,
+    pub entity: Entity,
+}
+
+// Sleeping players by entity index, written out after every change so they survive a restart.
+// Changes only happen in memory; whoever makes one hands pending() to a thread that may block.
+pub struct Sleepers {
+    path: PathBuf,
+    sleepers: HashMap<usize, Sleeper>,
+    // Counts changes, so a write that lost the race to a later one is skipped
+    changes: u64,
+    written: Arc<Mutex<u64>>,
+}
+impl Sleepers {
+    // Starts empty when there is nothing saved yet.
+    pub fn load(save_dir: &str) -> Result<Sleepers, String> {
+        let path = Path::new(save_dir).join("sleepers.bin");
+        let sleepers = match fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        Ok(Sleepers {
+            path: path,
+            sleepers: sleepers,
+            changes: 0,
+            written: Arc::new(Mutex::new(0)),
+        })
+    }
+    pub fn max_index(&self) -> Option<usize> {
+        self.sleepers.keys().max().cloned()
+    }
+    pub fn len(&self) -> usize {
+        self.sleepers.len()
+    }
+    pub fn insert(&mut self, sleeper: Sleeper) {
+        self.sleepers.insert(sleeper.session.entity_index, sleeper);
+        self.changes += 1;
+    }
+    // Only hands the sleeper back to the session it belonged to.
+    pub fn take(&mut self, session: &Session) -> Option<Sleeper> {
+        match self.sleepers.get(&session.entity_index) {
+            Some(s) if s.session == *session => {}
+            _ => return None,
+        }
+        self.changes += 1;
+        self.sleepers.remove(&session.entity_index)
+    }
+    // Everything as it is now, ready to be written without holding on to the sleepers.
+    pub fn pending(&self) -> Pending {
+        Pending {
+            path: self.path.clone(),
+            bytes: bincode::serialize(&self.sleepers)
+                .map_err(|e| format!("Failed to serialize sleepers: {}", e)),
+            change: self.changes,
+            written: Arc::clone(&self.written),
+        }
+    }
+}
+
+pub struct Pending {
+    path: PathBuf,
+    bytes: Result<Vec<u8>, String>,
+    change: u64,
+    written: Arc<Mutex<u64>>,
+}
+impl Pending {
+    // One write at a time, and none that would put back an older state than the disk has.
+    pub fn write(self) -> Result<(), String> {
+        let mut written = self.written.lock().unwrap();
+        if *written >= self.change {
+            return Ok(());
+        }
+        let bytes = self.bytes?;
+        let partial = self.path.with_extension("bin.partial");
+        self.path
+            .parent()
+            .map_or(Ok(()), |dir| fs::create_dir_all(dir))
+            .and_then(|_| fs::write(&partial, bytes))
+            .and_then(|_| fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        *written = self.change;
+        Ok(())
+    }
+}
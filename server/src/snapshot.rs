@@ -0,0 +1,102 @@
+use crate::worldgen::{
+    chunk_index, Chunk, Coords_i32, Entity, Settlement, Tile, World, CHUNK_SIZE, WORLD_SIZE,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Read-only copy of a world as it was at the end of a tick. Chunks are shared between
+// snapshots, only the ones whose hash changed get cloned again.
+#[derive(Clone, Debug)]
+pub struct WorldSnapshot {
+    pub chunks: Vec<Arc<Chunk>>,
+    pub settlements: Arc<Option<Vec<Settlement>>>,
+    pub time: u64,
+}
+impl WorldSnapshot {
+    pub fn from(world: &World) -> WorldSnapshot {
+        WorldSnapshot {
+            chunks: world.chunks.iter().map(|c| Arc::new(c.clone())).collect(),
+            settlements: Arc::new(world.settlements.clone()),
+            time: world.time,
+        }
+    }
+    pub fn next(&self, world: &World) -> WorldSnapshot {
+        let chunks = world
+            .chunks
+            .iter()
+            .zip(&self.chunks)
+            .map(|(chunk, old)| {
+                if chunk.hash == old.hash {
+                    Arc::clone(old)
+                } else {
+                    Arc::new(chunk.clone())
+                }
+            })
+            .collect();
+        WorldSnapshot {
+            chunks: chunks,
+            settlements: Arc::clone(&self.settlements),
+            time: world.time,
+        }
+    }
+    pub fn fetch_chunk(&self, index: usize) -> &Chunk {
+        &self.chunks[index]
+    }
+    pub fn fetch_chunk_x_y(&self, x: f32, y: f32) -> &Chunk {
+        let x_int = x as i32;
+        let y_int = y as i32;
+        &self.chunks[(y_int * *WORLD_SIZE as i32 + x_int) as usize]
+    }
+    pub fn fetch_entity(&self, index: usize) -> Option<&Entity> {
+        self.chunks
+            .iter()
+            .flat_map(|c| c.entities.iter())
+            .find(|e| e.index == index)
+    }
+    // x and y are in tiles, not world coordinates.
+    pub fn fetch_tile(&self, x: i32, y: i32) -> Option<&Tile> {
+        let size = *CHUNK_SIZE as i32;
+        let ccoords = Coords_i32::from((x.div_euclid(size), y.div_euclid(size), 0));
+        self.chunks[chunk_index(&ccoords)?].tile_at(x, y)
+    }
+}
+
+// Published by the simulation after every tick. Readers load whichever is newest and never
+// hold anything the simulation has to wait for.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub worlds: Vec<WorldSnapshot>,
+    // World of every entity placed by command, players among them, by entity index
+    pub locations: Arc<HashMap<usize, usize>>,
+}
+impl Snapshot {
+    pub fn from(worlds: &[World]) -> Snapshot {
+        Snapshot {
+            tick: 0,
+            worlds: worlds.iter().map(WorldSnapshot::from).collect(),
+            locations: Arc::new(HashMap::new()),
+        }
+    }
+    pub fn next(&self, tick: u64, worlds: &[World], locations: &HashMap<usize, usize>) -> Snapshot {
+        Snapshot {
+            tick: tick,
+            worlds: worlds
+                .iter()
+                .enumerate()
+                .map(|(i, w)| match self.worlds.get(i) {
+                    Some(old) if old.chunks.len() == w.chunks.len() => old.next(w),
+                    _ => WorldSnapshot::from(w),
+                })
+                .collect(),
+            locations: if *self.locations == *locations {
+                Arc::clone(&self.locations)
+            } else {
+                Arc::new(locations.clone())
+            },
+        }
+    }
+    pub fn locate(&self, index: usize) -> Option<usize> {
+        self.locations.get(&index).cloned()
+    }
+}
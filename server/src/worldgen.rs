@@ -4,20 +4,87 @@ use image::{imageops::FilterType, io::Reader as ImageReader, DynamicImage, Gener
 use lazy_static::lazy_static;
 use noise::{NoiseFn, Perlin};
 use rand::prelude::SliceRandom;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Dimensions {
+    pub world_size: u32,
+    pub chunk_size: u32,
+    pub tile_size: u32,
+    pub noise_scale: f64,
+}
+impl Dimensions {
+    pub fn new() -> Dimensions {
+        Dimensions {
+            world_size: 64,
+            chunk_size: 16,
+            tile_size: 16,
+            noise_scale: 64.0,
+        }
+    }
+    // Chunk and tile coordinates depend on the sizes alone; the noise scale only shapes terrain
+    // where it is generated.
+    pub fn lines_up_with(&self, other: &Dimensions) -> bool {
+        self.world_size == other.world_size
+            && self.chunk_size == other.chunk_size
+            && self.tile_size == other.tile_size
+    }
+}
+static DIMENSIONS: OnceLock<Dimensions> = OnceLock::new();
+// Has to happen before WORLD_SIZE and friends are first read; fails with the dimensions already in use.
+pub fn set_dimensions(new: Dimensions) -> Result<(), Dimensions> {
+    match DIMENSIONS.set(new.clone()) {
+        Ok(()) => Ok(()),
+        Err(_) if *dimensions() == new => Ok(()),
+        Err(_) => Err(dimensions().clone()),
+    }
+}
+pub fn dimensions() -> &'static Dimensions {
+    DIMENSIONS.get_or_init(Dimensions::new)
+}
+
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+// Everything random in the world draws from here. Generating or resolving a chunk reseeds it
+// from the seed and the chunk first, so the same inputs always make the same world whichever
+// thread the chunk lands on.
+#[derive(Clone, Copy, Debug)]
+pub struct WorldRng;
+impl RngCore for WorldRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|r| r.borrow_mut().next_u32())
+    }
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|r| r.borrow_mut().next_u64())
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|r| r.borrow_mut().fill_bytes(dest))
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RNG.with(|r| r.borrow_mut().try_fill_bytes(dest))
+    }
+}
+pub fn world_rng() -> WorldRng {
+    WorldRng
+}
+pub fn reseed(seed: u64, salt: u64) {
+    RNG.with(|r| *r.borrow_mut() = StdRng::seed_from_u64(seed.rotate_left(32) ^ salt));
+}
 lazy_static! {
-    pub static ref WORLD_SIZE: u32 = 64;
-    pub static ref CHUNK_SIZE: u32 = 16;
-    pub static ref TILE_SIZE: u32 = 16;
-    pub static ref NOISE_SCALE: f64 = 64.0;
+    pub static ref WORLD_SIZE: u32 = dimensions().world_size;
+    pub static ref CHUNK_SIZE: u32 = dimensions().chunk_size;
+    pub static ref TILE_SIZE: u32 = dimensions().tile_size;
+    pub static ref NOISE_SCALE: f64 = dimensions().noise_scale;
     pub static ref VICINITY_DIST: i32 = 2;
     pub static ref SETTLEMENT_NAMES: Vec<String> = vec![
         "Valenor".to_string(),
@@ -679,15 +746,15 @@ pub enum Gender {
 pub fn gen_human_name(faction: Faction, gender: &Gender) -> String {
     match gender {
         Gender::Male => HUMAN_NAMES_M
-            .choose(&mut rand::thread_rng())
+            .choose(&mut world_rng())
             .unwrap()
             .to_string(),
         Gender::Female => HUMAN_NAMES_M
-            .choose(&mut rand::thread_rng())
+            .choose(&mut world_rng())
             .unwrap()
             .to_string(),
         Gender::Other => HUMAN_NAMES_M
-            .choose(&mut rand::thread_rng())
+            .choose(&mut world_rng())
             .unwrap()
             .to_string(),
     }
@@ -756,7 +823,7 @@ impl Personality {
         Personality { aggression: 0 }
     }
     pub fn gen() -> Personality {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         Personality {
             aggression: rng.gen_range(0..100),
         }
@@ -812,7 +879,7 @@ impl Stats {
         }
     }
     pub fn gen() -> Stats {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         Stats {
             health: 100,
             hunger: 100,
@@ -842,7 +909,7 @@ impl Stats {
         }
     }
     pub fn gen_from_class(class: &Class) -> Stats {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         match class {
             Class::Detective => Stats {
                 health: 100,
@@ -982,7 +1049,7 @@ impl Stats {
         }
     }
     pub fn gen_plant() -> Stats {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         Stats {
             health: 100,
             hunger: 100,
@@ -1012,7 +1079,7 @@ impl Stats {
         }
     }
     pub fn gen_cat() -> Stats {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         Stats {
             health: 100,
             hunger: 100,
@@ -1042,7 +1109,7 @@ impl Stats {
         }
     }
     pub fn gen_crop() -> Stats {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         Stats {
             health: 100,
             hunger: 100,
@@ -1144,6 +1211,8 @@ pub enum EntityType {
     Landmine,
     Car,
     Cat,
+    // Linked to its counterpart in another world through linked_entity_id
+    Portal,
 }
 #[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq)]
 pub struct Coords_i32 {
@@ -1269,6 +1338,9 @@ pub struct Entity {
     pub tasks: Tasks,
     pub current_world: usize,
     pub linked_entity_id: u64,
+    // Sequence number of the last movement input the server applied. Clients replay anything
+    // newer on top of this state.
+    pub input_sequence: u64,
     pub class: Class,
     pub experience: i32,
     pub level: i32,
@@ -1296,6 +1368,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             level: 1,
             experience: 0,
             dialogue: None,
@@ -1335,6 +1408,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1368,6 +1442,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             level: 1,
             experience: 0,
             parts: vec![],
@@ -1400,6 +1475,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             level: 1,
             experience: 0,
             dialogue: None,
@@ -1431,6 +1507,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1463,6 +1540,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1495,6 +1573,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1531,6 +1610,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1567,6 +1647,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: 0,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1609,6 +1690,7 @@ impl Entity {
             tasks: Tasks::new(),
             current_world: current_world,
             linked_entity_id: 0,
+            input_sequence: 0,
             class: Class::Mailcarrier,
             level: 1,
             experience: 0,
@@ -1634,7 +1716,7 @@ impl Entity {
         if self.stats.hunger > 0 {
             self.stats.hunger -= 1;
         }
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         let roll = rng.gen_range(0..10);
         if self.stats.hunger == 0 {
             if self.stats.health >= 0 {
@@ -1654,22 +1736,14 @@ impl Entity {
         if self.etype == EntityType::Explosion {
             self.stats.health -= 16;
         }
-        if self.etype == EntityType::Cannon {
-            let now = SystemTime::now();
-            if now
-                .duration_since(UNIX_EPOCH)
-                .expect("Failed to assert time")
-                .as_millis()
-                % 256
-                == 0
-            {
-                self.fire();
-            }
+        // Left to chance rather than the clock, so a replay fires on the same ticks
+        if self.etype == EntityType::Cannon && rng.gen_range(0..256) == 0 {
+            self.fire();
         }
         if self.tasks.build.1 {}
     }
     pub fn resolve_against(&mut self, other: &mut Entity, step_increment: i32) {
-        let mut rng = rand::thread_rng();
+        let mut rng = world_rng();
         let roll = rng.gen_range(0..10);
         if dist_f32_f32(&self.coords, &other.coords) <= *VICINITY_DIST {
             if other.etype == EntityType::Explosion {
@@ -1752,8 +1826,92 @@ impl Tile {
         bincode::serialize(self)
     }
 }
+// Tiles on the regular chunk grid are sent as run-length encoded types plus a height array.
+// Anything else (held entities, designs, other sizes) is kept whole by position.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CompactTiles {
+    pub origin: (i32, i32),
+    pub ttypes: Vec<(TileType, u32)>,
+    pub heights: Vec<i32>,
+    pub irregular: Vec<(usize, Tile)>,
+}
+impl CompactTiles {
+    pub fn from(tiles: &[Tile]) -> CompactTiles {
+        let origin = tiles.first().map_or((0, 0), |t| (t.coords.x, t.coords.y));
+        let mut ttypes: Vec<(TileType, u32)> = vec![];
+        let mut heights = vec![];
+        let mut irregular = vec![];
+        for (i, t) in tiles.iter().enumerate() {
+            match ttypes.last_mut() {
+                Some((ttype, run)) if *ttype == t.ttype => *run += 1,
+                _ => ttypes.push((t.ttype.clone(), 1)),
+            }
+            heights.push(t.coords.z);
+            if !is_regular_tile(t, i, origin) {
+                irregular.push((i, t.clone()));
+            }
+        }
+        CompactTiles {
+            origin,
+            ttypes,
+            heights,
+            irregular,
+        }
+    }
+    pub fn expand(&self) -> Vec<Tile> {
+        let mut tiles = vec![];
+        let ttypes = self
+            .ttypes
+            .iter()
+            .flat_map(|(ttype, run)| std::iter::repeat(ttype).take(*run as usize));
+        for (i, (ttype, height)) in ttypes.zip(self.heights.iter()).enumerate() {
+            let (x, y) = regular_tile_coords(i, self.origin);
+            tiles.push(Tile::from(
+                Coords_i32::from((x, y, *height)),
+                i,
+                Size::from((*TILE_SIZE as i32, *TILE_SIZE as i32, *TILE_SIZE as i32)),
+                ttype.clone(),
+                None,
+            ));
+        }
+        for (i, t) in &self.irregular {
+            if let Some(tile) = tiles.get_mut(*i) {
+                *tile = t.clone();
+            }
+        }
+        tiles
+    }
+}
+fn regular_tile_coords(i: usize, origin: (i32, i32)) -> (i32, i32) {
+    (
+        origin.0 + (i % *CHUNK_SIZE as usize) as i32,
+        origin.1 + (i / *CHUNK_SIZE as usize) as i32,
+    )
+}
+fn is_regular_tile(t: &Tile, i: usize, origin: (i32, i32)) -> bool {
+    let (x, y) = regular_tile_coords(i, origin);
+    t.index == i
+        && t.coords.x == x
+        && t.coords.y == y
+        && t.size.x == *TILE_SIZE as i32
+        && t.size.y == *TILE_SIZE as i32
+        && t.size.z == *TILE_SIZE as i32
+        && t.holds.is_none()
+        && t.designed.is_none()
+}
+fn serialize_tiles<S: Serializer>(tiles: &Vec<Tile>, serializer: S) -> Result<S::Ok, S::Error> {
+    CompactTiles::from(tiles).serialize(serializer)
+}
+fn deserialize_tiles<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Tile>, D::Error> {
+    Ok(CompactTiles::deserialize(deserializer)?.expand())
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, Hash)]
 pub struct Chunk {
+    #[serde(
+        serialize_with = "serialize_tiles",
+        deserialize_with = "deserialize_tiles"
+    )]
     pub tiles: Vec<Tile>,
     pub entities: Vec<Entity>,
     pub settlement: Option<Settlement>,
@@ -1762,6 +1920,9 @@ pub struct Chunk {
     pub hash: u64,
     pub timezone: u8,
     pub observed: bool,
+    // Hash of the tiles alone, kept so entities moving about do not rehash every tile
+    #[serde(skip)]
+    tile_hash: Option<u64>,
 }
 
 impl Chunk {
@@ -1782,6 +1943,7 @@ impl Chunk {
             hash,
             timezone: timezone,
             observed: false,
+            tile_hash: None,
         }
     }
     pub fn as_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
@@ -1800,19 +1962,16 @@ impl Chunk {
             hash: 0,
             timezone: 0,
             observed: false,
+            tile_hash: None,
         }
     }
     pub fn resolve(&mut self, step_increment: i32) -> Vec<Entity> {
         if !self.observed {
             return vec![];
         }
-        let mut hasher = Sha256::new();
-        hasher.update(bincode::serialize(&self.tiles).unwrap());
-        hasher.update(bincode::serialize(&self.entities).unwrap());
         let mut added_entities: Vec<Entity> = vec![];
         let mut leftover_entities: Vec<Entity> = vec![];
-        let result = hasher.finalize();
-        self.hash = u64::from_le_bytes(result[0..8].try_into().expect("Failed to get 8 bytes"));
+        let mut dug = false;
         for i in 0..step_increment {
             for _t in &mut self.tiles {}
             let mut entities_clone = self.entities.clone();
@@ -1834,6 +1993,7 @@ impl Chunk {
                     s.vel.x = HashableF32(entity.ang.0.sin() * 1.0) * HashableF32(1.0);
                     s.vel.y = HashableF32(-entity.ang.0.cos() * 1.0) * HashableF32(1.0);
                     s.vel.z = HashableF32(entity.traj.0.cos() * 1.0) * HashableF32(0.5);
+                    s.current_world = entity.current_world;
                     added_entities.push(s);
                     entity.tasks.fire.1 = false;
                 }
@@ -1855,6 +2015,7 @@ impl Chunk {
                             e.coords.y.as_f32(),
                             e.coords.z.as_f32(),
                         );
+                        s.current_world = e.current_world;
                         added_entities.push(s);
                     }
                 }
@@ -1868,12 +2029,14 @@ impl Chunk {
                             if e.coords.z.as_i32() < t.coords.z {
                                 e.stats.health = -1;
                                 t.coords.z -= 1;
+                                dug = true;
                                 let mut s = Entity::gen_explosion(
                                     e.index + 1,
                                     e.coords.x.as_f32(),
                                     e.coords.y.as_f32(),
                                     e.coords.z.as_f32(),
                                 );
+                                s.current_world = e.current_world;
                                 added_entities.push(s);
                             }
                         }
@@ -1888,10 +2051,105 @@ impl Chunk {
                 .cloned()
                 .collect();
         }
+        if dug {
+            self.rehash();
+        } else {
+            self.rehash_entities();
+        }
         leftover_entities
     }
+    // The hash always describes the tiles and entities the chunk currently holds. This one is
+    // for after the tiles changed.
+    pub fn rehash(&mut self) {
+        let mut hasher = Sha256::new();
+        hasher.update(bincode::serialize(&self.tiles).unwrap());
+        let result = hasher.finalize();
+        let tile_hash = u64::from_le_bytes(result[0..8].try_into().expect("Failed to get 8 bytes"));
+        self.tile_hash = Some(tile_hash);
+        self.rehash_entities();
+    }
+    // When only entities changed the tiles keep the hash they had.
+    pub fn rehash_entities(&mut self) {
+        let tile_hash = match self.tile_hash {
+            Some(hash) => hash,
+            None => return self.rehash(),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(tile_hash.to_le_bytes());
+        hasher.update(bincode::serialize(&self.entities).unwrap());
+        let result = hasher.finalize();
+        self.hash = u64::from_le_bytes(result[0..8].try_into().expect("Failed to get 8 bytes"));
+    }
+    // Returns None when entities cannot be told apart by index, in which case the whole chunk has to be sent.
+    pub fn diff(&self, base: &Chunk) -> Option<ChunkDelta> {
+        if self.index != base.index || self.tiles.len() != base.tiles.len() {
+            return None;
+        }
+        if !unique_entity_indices(&self.entities) || !unique_entity_indices(&base.entities) {
+            return None;
+        }
+        let tiles = self
+            .tiles
+            .iter()
+            .zip(base.tiles.iter())
+            .enumerate()
+            .filter(|(_, (t, b))| t.as_bytes().ok() != b.as_bytes().ok())
+            .map(|(i, (t, _))| (i, t.clone()))
+            .collect();
+        let base_entities: HashMap<usize, Vec<u8>> = base
+            .entities
+            .iter()
+            .map(|e| (e.index, bincode::serialize(e).unwrap()))
+            .collect();
+        let entities = self
+            .entities
+            .iter()
+            .filter(|e| base_entities.get(&e.index) != Some(&bincode::serialize(e).unwrap()))
+            .cloned()
+            .collect();
+        let removed_entities = base
+            .entities
+            .iter()
+            .filter(|b| !self.entities.iter().any(|e| e.index == b.index))
+            .map(|b| b.index)
+            .collect();
+        Some(ChunkDelta {
+            index: self.index,
+            base_hash: base.hash,
+            hash: self.hash,
+            tiles: tiles,
+            entities: entities,
+            removed_entities: removed_entities,
+        })
+    }
+    // Returns false and leaves the chunk untouched if the delta was built against another version.
+    pub fn apply(&mut self, delta: &ChunkDelta) -> bool {
+        if self.index != delta.index || self.hash != delta.base_hash {
+            return false;
+        }
+        for (i, t) in &delta.tiles {
+            if let Some(tile) = self.tiles.get_mut(*i) {
+                *tile = t.clone();
+            }
+        }
+        self.entities
+            .retain(|e| !delta.removed_entities.contains(&e.index));
+        for entity in &delta.entities {
+            if let Some(existing_entity) = self.entities.iter_mut().find(|e| e.index == entity.index) {
+                *existing_entity = entity.clone();
+            } else {
+                self.entities.push(entity.clone());
+            }
+        }
+        if !delta.tiles.is_empty() {
+            self.tile_hash = None;
+        }
+        self.hash = delta.hash;
+        true
+    }
     pub fn gen(&mut self, seed: u32, img: Option<&DynamicImage>) -> Chunk {
-        let mut rng = rand::thread_rng();
+        reseed(seed as u64, self.index as u64);
+        let mut rng = world_rng();
         let mut tiles: Vec<Tile> = vec![];
         let mut entities: Vec<Entity> = vec![];
         let fac_perlin = Perlin::new(seed);
@@ -1962,7 +2220,7 @@ impl Chunk {
             if height < 0.0 {
                 ttype = TileType::Water;
             }
-            let gender = GENDERS.choose(&mut rand::thread_rng()).unwrap();
+            let gender = GENDERS.choose(&mut world_rng()).unwrap();
             if biome == "heartland" {
                 if height >= 0.0 && rng.gen_range(0..32) == 1 {
                     entities.push(Entity::from(
@@ -2203,11 +2461,21 @@ impl Chunk {
             hash: 0,
             timezone: self.timezone,
             observed: false,
+            tile_hash: None,
         }
     }
     pub fn fetch_tile(&self, index: usize) -> &Tile {
         &self.tiles[index]
     }
+    // x and y are in tiles, counted from the world origin.
+    pub fn tile_at(&self, x: i32, y: i32) -> Option<&Tile> {
+        let size = *CHUNK_SIZE as i32;
+        let i = (y.rem_euclid(size) * size + x.rem_euclid(size)) as usize;
+        match self.tiles.get(i) {
+            Some(t) if t.coords.x == x && t.coords.y == y => Some(t),
+            _ => self.tiles.iter().find(|t| t.coords.x == x && t.coords.y == y),
+        }
+    }
     pub fn inquire_news(&self) -> News {
         let mut news = vec![];
         let mut coin_count = 0;
@@ -2221,6 +2489,44 @@ impl Chunk {
         News::from(news)
     }
 }
+fn unique_entity_indices(entities: &[Entity]) -> bool {
+    let mut seen = HashSet::new();
+    entities.iter().all(|e| seen.insert(e.index))
+}
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChunkDelta {
+    pub index: usize,
+    pub base_hash: u64,
+    pub hash: u64,
+    pub tiles: Vec<(usize, Tile)>,
+    pub entities: Vec<Entity>,
+    pub removed_entities: Vec<usize>,
+}
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ChunkUpdate {
+    Full(Chunk),
+    Delta(ChunkDelta),
+}
+impl ChunkUpdate {
+    pub fn index(&self) -> usize {
+        match self {
+            ChunkUpdate::Full(c) => c.index,
+            ChunkUpdate::Delta(d) => d.index,
+        }
+    }
+    pub fn hash(&self) -> u64 {
+        match self {
+            ChunkUpdate::Full(c) => c.hash,
+            ChunkUpdate::Delta(d) => d.hash,
+        }
+    }
+    pub fn entities(&self) -> &Vec<Entity> {
+        match self {
+            ChunkUpdate::Full(c) => &c.entities,
+            ChunkUpdate::Delta(d) => &d.entities,
+        }
+    }
+}
 #[derive(Clone, Serialize, Deserialize, Debug, Hash)]
 pub struct News {
     pub newscast: Vec<String>,
@@ -2289,11 +2595,38 @@ impl World {
             // Add the new entity
             chunk.entities.push(entity);
         }
+        chunk.rehash_entities();
+    }
+    pub fn fetch_entity(&self, index: usize) -> Option<&Entity> {
+        self.chunks
+            .iter()
+            .flat_map(|c| c.entities.iter())
+            .find(|e| e.index == index)
+    }
+    // x and y are in tiles, not world coordinates.
+    pub fn fetch_tile(&self, x: i32, y: i32) -> Option<&Tile> {
+        let size = *CHUNK_SIZE as i32;
+        let ccoords = Coords_i32::from((x.div_euclid(size), y.div_euclid(size), 0));
+        self.chunks[chunk_index(&ccoords)?].tile_at(x, y)
+    }
+    pub fn remove_entity(&mut self, index: usize) -> Option<Entity> {
+        for chunk in &mut self.chunks {
+            if let Some(i) = chunk.entities.iter().position(|e| e.index == index) {
+                let entity = chunk.entities.remove(i);
+                chunk.rehash_entities();
+                return Some(entity);
+            }
+        }
+        None
     }
     pub fn resolve(&mut self, delta: f32, step_increment: i32) {
         self.time += delta as u64;
         let mut leftover_entities = vec![];
+        let time = self.time;
         self.chunks.iter_mut().for_each(|c| {
+            if c.observed {
+                reseed(time, c.index as u64);
+            }
             let los = c.resolve(step_increment);
             leftover_entities.extend(los);
             c.observed = false;
@@ -2304,12 +2637,17 @@ impl World {
     }
     pub fn resolve_between(&mut self, step_increment: i32) {}
 }
+pub fn chunk_index(ccoords: &Coords_i32) -> Option<usize> {
+    if ccoords.x < 0
+        || ccoords.y < 0
+        || ccoords.x > *WORLD_SIZE as i32 - 1
+        || ccoords.y > *WORLD_SIZE as i32 - 1
+    {
+        return None;
+    }
+    Some((ccoords.y * *WORLD_SIZE as i32 + ccoords.x) as usize)
+}
 pub fn worldgen(seed: u32) -> World {
-    let image_path = "data/map/globe.gif";
-    let img = ImageReader::open(image_path)
-        .expect("Failed to open image")
-        .decode()
-        .expect("Failed to decode image");
     let mut chunks: Vec<Chunk> = vec![];
     for c in 0..((*WORLD_SIZE * *WORLD_SIZE) as i32) {
         let x = (c % *WORLD_SIZE as i32) as f32;
@@ -2326,6 +2664,7 @@ pub fn worldgen(seed: u32) -> World {
     }
     chunks.par_iter_mut().for_each(|c| *c = c.gen(seed, None));
     let settlements: Vec<Settlement> = chunks.par_iter_mut().map(|c| {
+        reseed(seed as u64, c.index as u64);
         let mut settlement = Settlement::new();
         let mut faction_counts: HashMap<Faction, usize> = HashMap::new();
         for e in &c.entities {
@@ -2343,7 +2682,7 @@ pub fn worldgen(seed: u32) -> World {
             .unwrap_or(Faction::Empty); // Fallback in case there are no factions
 	
         let mut settlement_name = SETTLEMENT_NAMES
-            .choose(&mut rand::thread_rng())
+            .choose(&mut world_rng())
             .unwrap()
             .to_string();
 	if largest_faction == Faction::Empty {
@@ -2368,8 +2707,7 @@ pub fn worldgen(seed: u32) -> World {
     let world = World::from(chunks, Some(settlements), 0);
     world
 }
-pub fn globegen() -> World {
-    let image_path = "data/map/globe.gif";
+pub fn globegen(image_path: &str) -> World {
     let mut img = ImageReader::open(image_path)
         .expect("Failed to open image")
         .decode()